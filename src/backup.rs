@@ -0,0 +1,382 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Retention;
+use crate::database::Entry;
+use crate::errors::*;
+
+/// Why a backup was taken
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Reason {
+    /// Destination was overwritten by a render
+    Update,
+    /// Template changes were merged into an edited destination
+    Merge,
+    /// Destination was replaced by an older backup
+    Restore,
+}
+
+impl fmt::Display for Reason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Reason::Update => "update",
+            Reason::Merge => "merge",
+            Reason::Restore => "restore",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct BackupRecord {
+    pub destination: PathBuf,
+    pub backup: PathBuf,
+    pub timestamp: SystemTime,
+    pub reason: Reason,
+}
+
+impl fmt::Display for BackupRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}  {:<8} {} -> {}",
+            DateTime::<Local>::from(self.timestamp).format("%Y-%m-%d %H:%M:%S"),
+            self.reason,
+            self.destination.display(),
+            self.backup.display()
+        )
+    }
+}
+
+/// State of a single destination before a transaction changed it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Change {
+    pub destination: PathBuf,
+    /// First backup taken during the transaction, None if the destination
+    /// did not exist before
+    pub backup: Option<PathBuf>,
+    /// Database entry before the transaction
+    pub entry: Option<Entry>,
+    /// The destination was adopted by `update --bootstrap` without writing it,
+    /// so undoing only forgets its entry
+    #[serde(default)]
+    pub adopted: bool,
+}
+
+/// Changes made by one `update` run, undone together by `rotfiles undo`
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Transaction {
+    pub id: u64,
+    pub timestamp: SystemTime,
+    pub changes: Vec<Change>,
+}
+
+impl Transaction {
+    pub fn new(id: u64) -> Self {
+        Self {
+            id,
+            timestamp: SystemTime::now(),
+            changes: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:>4}  {}  {} files",
+            self.id,
+            DateTime::<Local>::from(self.timestamp).format("%Y-%m-%d %H:%M:%S"),
+            self.changes.len()
+        )?;
+        for change in &self.changes {
+            write!(f, "\n\t{}", change.destination.display())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct IndexData {
+    #[serde(default)]
+    backups: Vec<BackupRecord>,
+    #[serde(default)]
+    transactions: Vec<Transaction>,
+}
+
+/// List of backups taken by rotfiles, stored in backups.json next to database.json
+pub struct BackupIndex {
+    fname: PathBuf,
+    data: IndexData,
+    read_only: bool,
+}
+
+impl BackupIndex {
+    pub fn connect(cfg: &crate::config::Config) -> Result<Self> {
+        let mut res = Self::connect_read_only(cfg)?;
+        res.read_only = false;
+        Ok(res)
+    }
+
+    /// Opens the index without ever writing backups.json
+    pub fn connect_read_only(cfg: &crate::config::Config) -> Result<Self> {
+        let fname = cfg.backup_path.join("backups.json");
+        let data = if fname.exists() {
+            let file = File::open(&fname).chain_err(|| "Couldn't open backup index")?;
+            serde_json::from_reader(file).chain_err(|| "Error decoding backup index")?
+        } else {
+            IndexData::default()
+        };
+
+        Ok(Self {
+            fname,
+            data,
+            read_only: true,
+        })
+    }
+
+    pub fn commit(&self) -> Result<()> {
+        if self.read_only {
+            bail!("Backup index was opened read-only");
+        }
+        crate::ensure_parent_exists(&self.fname)?;
+        let file = File::create(&self.fname).chain_err(|| "Couldn't overwrite backup index")?;
+        serde_json::to_writer_pretty(file, &self.data).chain_err(|| "Error writing data")?;
+
+        Ok(())
+    }
+
+    pub fn add(&mut self, record: BackupRecord) {
+        self.data.backups.push(record);
+    }
+
+    /// Forgets about the backup stored at `backup`, without deleting it.
+    /// Transactions that relied on it can no longer be undone and are dropped.
+    pub fn remove(&mut self, backup: &Path) -> Option<BackupRecord> {
+        let idx = self.data.backups.iter().position(|r| r.backup == backup)?;
+        self.data
+            .transactions
+            .retain(|t| t.changes.iter().all(|c| c.backup.as_deref() != Some(backup)));
+        Some(self.data.backups.remove(idx))
+    }
+
+    pub fn next_transaction_id(&self) -> u64 {
+        self.data.transactions.iter().map(|t| t.id + 1).max().unwrap_or(1)
+    }
+
+    pub fn add_transaction(&mut self, transaction: Transaction) {
+        self.data.transactions.push(transaction);
+    }
+
+    /// All transactions, oldest first
+    pub fn transactions(&self) -> impl Iterator<Item = &Transaction> {
+        self.data.transactions.iter()
+    }
+
+    /// Transaction with the given id, or the last one
+    pub fn transaction(&self, id: Option<u64>) -> Option<&Transaction> {
+        match id {
+            Some(id) => self.transactions().find(|t| t.id == id),
+            None => self.data.transactions.last(),
+        }
+    }
+
+    pub fn remove_transaction(&mut self, id: u64) -> Option<Transaction> {
+        let idx = self.data.transactions.iter().position(|t| t.id == id)?;
+        Some(self.data.transactions.remove(idx))
+    }
+
+    /// All backups, oldest first
+    pub fn all(&self) -> impl Iterator<Item = &BackupRecord> {
+        self.data.backups.iter()
+    }
+
+    /// Backups of a single destination, oldest first
+    pub fn for_destination<'a>(
+        &'a self,
+        destination: &'a Path,
+    ) -> impl Iterator<Item = &'a BackupRecord> {
+        self.all().filter(move |r| r.destination == destination)
+    }
+
+    /// Newest backup of `destination` taken no later than `at`
+    pub fn find<'a>(
+        &'a self,
+        destination: &'a Path,
+        at: Option<SystemTime>,
+    ) -> Option<&'a BackupRecord> {
+        self.for_destination(destination)
+            .filter(|r| at.is_none_or(|t| r.timestamp <= t))
+            .max_by_key(|r| r.timestamp)
+    }
+}
+
+// See Database for why this is disabled in tests
+#[cfg(not(test))]
+impl Drop for BackupIndex {
+    fn drop(&mut self) {
+        if self.read_only {
+            return;
+        }
+        debug!("Commiting backup index on drop");
+        self.commit().unwrap();
+    }
+}
+
+/// Picks the backups that `retention` no longer keeps.
+/// `backups` pairs every record with the size of its backup file.
+pub fn expired_backups(
+    retention: &Retention,
+    backups: &[(BackupRecord, u64)],
+    now: SystemTime,
+) -> Result<Vec<PathBuf>> {
+    let keep_within = match retention.keep_within {
+        Some(ref s) => Some(
+            humantime::parse_duration(s)
+                .chain_err(|| format!("Invalid keep_within duration {}", s))?,
+        ),
+        None => None,
+    };
+    let time_rules = retention.keep_last.is_some() || keep_within.is_some();
+
+    let mut by_destination: BTreeMap<&Path, Vec<&(BackupRecord, u64)>> = BTreeMap::new();
+    for b in backups {
+        by_destination.entry(&b.0.destination).or_default().push(b);
+    }
+
+    let mut expired = Vec::new();
+    // (record, size, whether it's the newest backup of its file)
+    let mut kept = Vec::new();
+    for list in by_destination.values_mut() {
+        list.sort_by_key(|b| std::cmp::Reverse(b.0.timestamp));
+        for (i, (record, size)) in list.iter().map(|b| (&b.0, b.1)).enumerate() {
+            let age = now.duration_since(record.timestamp).unwrap_or_default();
+            let keep = !time_rules
+                || retention.keep_last.is_some_and(|n| i < n)
+                || keep_within.is_some_and(|d| age <= d);
+            if keep {
+                kept.push((record, size, i == 0));
+            } else {
+                expired.push(record.backup.clone());
+            }
+        }
+    }
+
+    if let Some(max) = retention.max_total_size {
+        let mut total: u64 = kept.iter().map(|k| k.1).sum();
+        kept.sort_by_key(|k| k.0.timestamp);
+        for (record, size, newest) in kept {
+            if total <= max {
+                break;
+            }
+            if !newest {
+                expired.push(record.backup.clone());
+                total -= size;
+            }
+        }
+    }
+
+    Ok(expired)
+}
+
+/// Parses times given on the command line, either RFC 3339 or local time
+/// as `2020-02-21T10:00:00`, `2020-02-21 10:00:00` or `2020-02-21`
+pub fn parse_time(s: &str) -> Result<SystemTime> {
+    if let Ok(t) = DateTime::parse_from_rfc3339(s) {
+        return Ok(t.into());
+    }
+    let naive = NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M:%S")
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S"))
+        .or_else(|_| NaiveDate::parse_from_str(s, "%Y-%m-%d").map(|d| d.and_hms(0, 0, 0)))
+        .chain_err(|| format!("Could not parse time {}", s))?;
+    match Local.from_local_datetime(&naive).earliest() {
+        Some(t) => Ok(t.into()),
+        None => bail!("Time {} does not exist in local timezone", s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn record(destination: &str, age_days: u64) -> BackupRecord {
+        let now = UNIX_EPOCH + Duration::from_secs(100 * 86400);
+        BackupRecord {
+            destination: PathBuf::from(destination),
+            backup: PathBuf::from(format!("{}-{}", destination, age_days)),
+            timestamp: now - Duration::from_secs(age_days * 86400),
+            reason: Reason::Update,
+        }
+    }
+
+    fn expired(retention: &Retention, backups: &[(BackupRecord, u64)]) -> Vec<PathBuf> {
+        let now = UNIX_EPOCH + Duration::from_secs(100 * 86400);
+        let mut res = expired_backups(retention, backups, now).unwrap();
+        res.sort();
+        res
+    }
+
+    #[test]
+    fn test_retention() {
+        let backups: Vec<_> = [("a", 1), ("a", 5), ("a", 40), ("b", 2), ("b", 60)]
+            .iter()
+            .map(|(d, age)| (record(d, *age), 10))
+            .collect();
+        let paths = |names: &[&str]| names.iter().map(PathBuf::from).collect::<Vec<_>>();
+
+        assert!(expired(&Retention::default(), &backups).is_empty());
+
+        let keep_last = Retention {
+            keep_last: Some(1),
+            ..Retention::default()
+        };
+        assert_eq!(paths(&["a-40", "a-5", "b-60"]), expired(&keep_last, &backups));
+
+        let keep_within = Retention {
+            keep_within: Some("30days".to_owned()),
+            ..Retention::default()
+        };
+        assert_eq!(paths(&["a-40", "b-60"]), expired(&keep_within, &backups));
+
+        let both = Retention {
+            keep_last: Some(1),
+            keep_within: Some("3days".to_owned()),
+            ..Retention::default()
+        };
+        assert_eq!(paths(&["a-40", "a-5", "b-60"]), expired(&both, &backups));
+
+        // the newest backup of every file survives the size limit
+        let size = Retention {
+            max_total_size: Some(25),
+            ..Retention::default()
+        };
+        assert_eq!(paths(&["a-40", "a-5", "b-60"]), expired(&size, &backups));
+
+        let invalid = Retention {
+            keep_within: Some("a while".to_owned()),
+            ..Retention::default()
+        };
+        assert!(expired_backups(&invalid, &backups, SystemTime::now()).is_err());
+    }
+
+    #[test]
+    fn test_parse_time() {
+        let utc: SystemTime = Utc.ymd(2020, 2, 21).and_hms(10, 0, 0).into();
+        assert_eq!(utc, parse_time("2020-02-21T10:00:00+00:00").unwrap());
+
+        let local: SystemTime = Local.ymd(2020, 2, 21).and_hms(10, 0, 0).into();
+        assert_eq!(local, parse_time("2020-02-21T10:00:00").unwrap());
+        assert_eq!(local, parse_time("2020-02-21 10:00:00").unwrap());
+
+        assert!(parse_time("yesterday").is_err());
+    }
+}
@@ -1,12 +1,12 @@
+#![allow(clippy::result_large_err)]
 use rotfiles::database::*;
 use rotfiles::errors::*;
 #[macro_use]
 extern crate error_chain;
-use std::time::SystemTime;
 
 fn run() -> Result<()> {
     println!("Hello additional binary!");
-    let cfg = rotfiles::config::Config::from_file("/home/brych/.config/rotfiles/config.json")?;
+    let cfg = rotfiles::config::Config::from_file(rotfiles::config::Config::locate(None))?;
 
     let mut db = Database::connect(&cfg)?;
     let entry = make_test_entry(&cfg, "testfile");
@@ -18,11 +18,9 @@ fn run() -> Result<()> {
 }
 
 fn make_test_entry(cfg: &rotfiles::config::Config, subpath: &str) -> Entry {
-    Entry {
-        template_path: cfg.dot_path.join(subpath),
-        config_path: None,
-        destination: cfg.home_path.join(String::from(".") + subpath),
-        last_updated: SystemTime::now(),
-    }
+    Entry::new(
+        cfg.dot_path.join(subpath),
+        cfg.home_path.join(String::from(".") + subpath),
+    )
 }
 quick_main!(run);
@@ -0,0 +1,145 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::path::PathBuf;
+use std::time::{Duration, Instant, SystemTime};
+
+use subprocess::{Exec, ExitStatus, Redirection};
+
+use crate::config::Command;
+use crate::errors::*;
+
+/// How long a command may run when its config doesn't say otherwise
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Runs `cmd` with `sh -c` and returns its stdout without the trailing newline.
+/// Fails if the command exits unsuccessfully or runs longer than `timeout`.
+pub fn run(cmd: &str, timeout: Duration) -> Result<String> {
+    let start = Instant::now();
+    let mut popen = Exec::shell(cmd)
+        .stdout(Redirection::Pipe)
+        .stderr(Redirection::Pipe)
+        .popen()
+        .chain_err(|| format!("Could not run {}", cmd))?;
+
+    let read = popen
+        .communicate_start(None)
+        .limit_time(timeout)
+        .read_string();
+    let remaining = timeout.checked_sub(start.elapsed()).unwrap_or_default();
+    let status = match read {
+        Ok(_) => popen
+            .wait_timeout(remaining)
+            .chain_err(|| format!("Could not wait for {}", cmd))?,
+        Err(_) => None,
+    };
+
+    match (status, read) {
+        (Some(ExitStatus::Exited(0)), Ok((stdout, _))) => {
+            let stdout = stdout.unwrap_or_default();
+            Ok(stdout.trim_end_matches('\n').to_owned())
+        }
+        (Some(status), Ok((_, stderr))) => bail!(
+            "{} failed with {:?}: {}",
+            cmd,
+            status,
+            stderr.unwrap_or_default().trim_end()
+        ),
+        _ => {
+            let _ = popen.kill();
+            let _ = popen.wait();
+            bail!("{} timed out after {:?}", cmd, timeout)
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct CachedOutput {
+    run: String,
+    output: String,
+    timestamp: SystemTime,
+}
+
+/// Outputs of commands from the config, stored in commands.json next to
+/// database.json so they survive between runs
+pub struct CommandCache {
+    fname: PathBuf,
+    data: BTreeMap<String, CachedOutput>,
+}
+
+impl CommandCache {
+    pub fn open(cfg: &crate::config::Config) -> Result<Self> {
+        let fname = cfg.backup_path.join("commands.json");
+        let data = if fname.exists() {
+            let file = File::open(&fname).chain_err(|| "Couldn't open command cache")?;
+            serde_json::from_reader(file).chain_err(|| "Error decoding command cache")?
+        } else {
+            BTreeMap::new()
+        };
+        Ok(Self { fname, data })
+    }
+
+    pub fn commit(&self) -> Result<()> {
+        crate::ensure_parent_exists(&self.fname)?;
+        let file = File::create(&self.fname).chain_err(|| "Couldn't overwrite command cache")?;
+        serde_json::to_writer_pretty(file, &self.data).chain_err(|| "Error writing data")?;
+        Ok(())
+    }
+
+    /// Output of the command called `name`, from the cache if it is still fresh.
+    /// Returns whether the command had to be run.
+    pub fn output(&mut self, name: &str, command: &Command) -> Result<(String, bool)> {
+        let max_age = match command.cache {
+            Some(ref s) => Some(
+                humantime::parse_duration(s)
+                    .chain_err(|| format!("Invalid cache duration {}", s))?,
+            ),
+            None => None,
+        };
+        if let (Some(max_age), Some(cached)) = (max_age, self.data.get(name)) {
+            let age = SystemTime::now()
+                .duration_since(cached.timestamp)
+                .unwrap_or_default();
+            if cached.run == command.run && age <= max_age {
+                return Ok((cached.output.clone(), false));
+            }
+        }
+
+        let timeout = match command.timeout {
+            Some(ref s) => {
+                humantime::parse_duration(s).chain_err(|| format!("Invalid timeout {}", s))?
+            }
+            None => DEFAULT_TIMEOUT,
+        };
+        let output = run(&command.run, timeout)
+            .chain_err(|| format!("Could not compute command {}", name))?;
+        if max_age.is_some() {
+            self.data.insert(
+                name.to_owned(),
+                CachedOutput {
+                    run: command.run.clone(),
+                    output: output.clone(),
+                    timestamp: SystemTime::now(),
+                },
+            );
+        }
+        Ok((output, true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        let timeout = Duration::from_secs(5);
+        assert_eq!("hello", run("echo hello", timeout).unwrap());
+        assert_eq!("a\nb", run("printf 'a\\nb\\n\\n'", timeout).unwrap());
+        assert!(run("echo oops >&2; exit 3", timeout).is_err());
+
+        let start = Instant::now();
+        assert!(run("sleep 10", Duration::from_millis(200)).is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+}
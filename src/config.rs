@@ -1,4 +1,7 @@
 use serde::{Deserialize, Serialize};
+use serde_json::Value as Json;
+use std::collections::BTreeMap;
+use std::ffi::OsString;
 use std::fs::File;
 use std::path::{Path, PathBuf};
 
@@ -7,15 +10,85 @@ pub struct Config {
     pub home_path: PathBuf,
     pub dot_path: PathBuf,
     pub backup_path: PathBuf,
+    /// Environment variables exposed to templates as `env.NAME`
+    #[serde(default)]
+    pub env: Vec<String>,
+    // tables have to come after plain values for toml
+    #[serde(default)]
+    pub retention: Retention,
+    /// Named data layers, one of which can be selected with `--profile`
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Json>,
+    /// Commands whose output is exposed to templates as `commands.NAME`
+    #[serde(default)]
+    pub commands: BTreeMap<String, Command>,
+}
+
+/// Shell command run at render time
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Command {
+    pub run: String,
+    /// How long the command may run, e.g. "500ms". Defaults to 5 seconds.
+    pub timeout: Option<String>,
+    /// How long the output is reused for, e.g. "1day". Not cached by default.
+    pub cache: Option<String>,
+}
+
+/// Which backups survive garbage collection. With nothing set, all of them do.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Retention {
+    /// Keep this many newest backups of every file
+    pub keep_last: Option<usize>,
+    /// Keep every backup younger than this, e.g. "30days" or "2weeks"
+    pub keep_within: Option<String>,
+    /// Remove oldest backups until they take at most this many bytes.
+    /// The newest backup of each file is never removed by this rule.
+    pub max_total_size: Option<u64>,
 }
 
 impl Config {
+    /// Loads the config from json, or from toml if the file ends with `.toml`,
+    /// expanding `~` and environment variables in its paths
     pub fn from_file<P: AsRef<Path>>(path: P) -> std::io::Result<Config> {
-        let file = File::open(&path)?;
-        let res: Config = serde_json::from_reader(file)?;
+        let mut res: Config = if is_toml(path.as_ref()) {
+            let contents = std::fs::read_to_string(&path)?;
+            toml::from_str(&contents)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))?
+        } else {
+            let file = File::open(&path)?;
+            serde_json::from_reader(file)?
+        };
+        res.expand()?;
         Ok(res)
     }
 
+    /// Serializes the config in the format `from_file` expects for `path`
+    pub fn to_string_for<P: AsRef<Path>>(&self, path: P) -> std::io::Result<String> {
+        if is_toml(path.as_ref()) {
+            toml::to_string_pretty(self)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidData, e))
+        } else {
+            Ok(serde_json::to_string_pretty(self)?)
+        }
+    }
+
+    /// Expands `~` and environment variables in all paths
+    pub fn expand(&mut self) -> std::io::Result<()> {
+        for p in &mut [&mut self.home_path, &mut self.dot_path, &mut self.backup_path] {
+            **p = expand_path(p)?;
+        }
+        Ok(())
+    }
+
+    /// Where the config file is looked for, first match wins:
+    /// the `--config` option, `$ROTFILES_CONFIG`, then `config.json` or
+    /// `config.toml` in `$XDG_CONFIG_HOME/rotfiles`, or in `~/.config/rotfiles`
+    /// when `$XDG_CONFIG_HOME` is unset. If neither file exists yet,
+    /// `config.json` in that directory is where it should be created.
+    pub fn locate(explicit: Option<&Path>) -> PathBuf {
+        locate_with(explicit, |name| std::env::var_os(name))
+    }
+
     pub fn new<P, R, S>(home_path: P, dot_path: R, backup_path: S) -> Self
     where
         P: AsRef<Path>,
@@ -26,6 +99,178 @@ impl Config {
             home_path: home_path.as_ref().to_owned(),
             dot_path: dot_path.as_ref().to_owned(),
             backup_path: backup_path.as_ref().to_owned(),
+            retention: Retention::default(),
+            profiles: BTreeMap::new(),
+            env: Vec::new(),
+            commands: BTreeMap::new(),
+        }
+    }
+
+    /// Default layout for `home_path`: templates in `~/dotfiles`
+    /// and backups in `~/.local/share/rotfiles/backup`
+    pub fn with_home<P: AsRef<Path>>(home_path: P) -> Self {
+        let home = home_path.as_ref();
+        Self::new(
+            home,
+            home.join("dotfiles"),
+            home.join(".local/share/rotfiles/backup"),
+        )
+    }
+}
+
+fn locate_with<F>(explicit: Option<&Path>, var: F) -> PathBuf
+where
+    F: Fn(&str) -> Option<OsString>,
+{
+    if let Some(p) = explicit {
+        return p.to_owned();
+    }
+    if let Some(p) = var("ROTFILES_CONFIG") {
+        return PathBuf::from(p);
+    }
+    // an empty XDG_CONFIG_HOME counts as unset, as the XDG spec says
+    let dir = match var("XDG_CONFIG_HOME").filter(|xdg| !xdg.is_empty()) {
+        Some(xdg) => PathBuf::from(xdg).join("rotfiles"),
+        None => {
+            let home = var("HOME").map(PathBuf::from).unwrap_or_default();
+            home.join(".config/rotfiles")
         }
+    };
+    ["config.json", "config.toml"]
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|p| p.exists())
+        .unwrap_or_else(|| dir.join("config.json"))
+}
+
+fn is_toml(path: &Path) -> bool {
+    path.extension() == Some("toml".as_ref())
+}
+
+fn expand_path(path: &Path) -> std::io::Result<PathBuf> {
+    let s = path.to_string_lossy();
+    let expanded = shellexpand::full(&s).map_err(|e| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("Could not expand path {}: {}", s, e),
+        )
+    })?;
+    Ok(PathBuf::from(expanded.as_ref()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locate() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let xdg = tmp.path().join("xdg");
+        let vars = |with_env: bool| {
+            let xdg = xdg.clone();
+            move |name: &str| match name {
+                "ROTFILES_CONFIG" if with_env => Some(OsString::from("/env/config.json")),
+                "XDG_CONFIG_HOME" => Some(xdg.clone().into_os_string()),
+                "HOME" => Some(OsString::from("/home/user")),
+                _ => None,
+            }
+        };
+        let no_xdg = |name: &str| match name {
+            "HOME" => Some(OsString::from("/home/user")),
+            _ => None,
+        };
+
+        assert_eq!(
+            PathBuf::from("/cli/config.json"),
+            locate_with(Some(Path::new("/cli/config.json")), vars(true))
+        );
+        assert_eq!(
+            PathBuf::from("/env/config.json"),
+            locate_with(None, vars(true))
+        );
+        assert_eq!(
+            PathBuf::from("/home/user/.config/rotfiles/config.json"),
+            locate_with(None, no_xdg)
+        );
+        assert_eq!(
+            xdg.join("rotfiles/config.json"),
+            locate_with(None, vars(false))
+        );
+
+        std::fs::create_dir_all(xdg.join("rotfiles")).unwrap();
+        std::fs::write(xdg.join("rotfiles/config.toml"), "").unwrap();
+        assert_eq!(
+            xdg.join("rotfiles/config.toml"),
+            locate_with(None, vars(false))
+        );
+        std::fs::write(xdg.join("rotfiles/config.json"), "{}").unwrap();
+        assert_eq!(
+            xdg.join("rotfiles/config.json"),
+            locate_with(None, vars(false))
+        );
+    }
+
+    #[test]
+    fn test_toml_config() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path().join("config.toml");
+        std::fs::write(
+            &path,
+            r#"
+            # where things live
+            home_path = "/home/user"
+            dot_path = "/home/user/dotfiles"
+            backup_path = "/home/user/.backup"
+            env = ["DISPLAY"]
+
+            [retention]
+            keep_last = 5
+
+            [profiles.work]
+            email = "me@work.com"
+
+            [commands.gpu]
+            run = "lspci | grep VGA"
+            cache = "1day"
+            "#,
+        )
+        .unwrap();
+
+        let cfg = Config::from_file(&path).unwrap();
+        assert_eq!(PathBuf::from("/home/user/dotfiles"), cfg.dot_path);
+        assert_eq!(Some(5), cfg.retention.keep_last);
+        assert_eq!("me@work.com", cfg.profiles["work"]["email"]);
+        assert_eq!(Some("1day".to_owned()), cfg.commands["gpu"].cache);
+
+        std::fs::write(&path, cfg.to_string_for(&path).unwrap()).unwrap();
+        let reloaded = Config::from_file(&path).unwrap();
+        assert_eq!(cfg.backup_path, reloaded.backup_path);
+        assert_eq!(cfg.profiles, reloaded.profiles);
+        assert_eq!(cfg.env, reloaded.env);
+        assert_eq!(cfg.commands, reloaded.commands);
+    }
+
+    #[test]
+    fn test_expand_paths() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let path = tmp.path().join("config.json");
+        std::fs::write(
+            &path,
+            r#"{"home_path": "~", "dot_path": "~/dotfiles", "backup_path": "$HOME/backup"}"#,
+        )
+        .unwrap();
+        let home = PathBuf::from(std::env::var("HOME").unwrap());
+
+        let cfg = Config::from_file(&path).unwrap();
+        assert_eq!(home, cfg.home_path);
+        assert_eq!(home.join("dotfiles"), cfg.dot_path);
+        assert_eq!(home.join("backup"), cfg.backup_path);
+
+        std::fs::write(
+            &path,
+            r#"{"home_path": "$ROTFILES_SURELY_UNSET", "dot_path": "", "backup_path": ""}"#,
+        )
+        .unwrap();
+        assert!(Config::from_file(&path).is_err());
     }
 }
@@ -0,0 +1,154 @@
+use serde_json::{Map, Value as Json};
+use std::path::{Path, PathBuf};
+
+use crate::errors::*;
+
+/// Extensions of template data files, in the order they are looked for
+pub const EXTENSIONS: [&str; 4] = ["json", "toml", "yaml", "yml"];
+
+/// `base` with a data file extension appended, e.g. `bashrc.toml` for `bashrc`
+fn with_extension(base: &Path, ext: &str) -> PathBuf {
+    let mut res = base.as_os_str().to_owned();
+    res.push(".");
+    res.push(ext);
+    res.into()
+}
+
+/// Every existing data file for `base`
+pub fn data_files(base: &Path) -> Vec<PathBuf> {
+    EXTENSIONS
+        .iter()
+        .map(|ext| with_extension(base, ext))
+        .filter(|p| p.exists())
+        .collect()
+}
+
+/// Data file for `base`, preferring json over toml over yaml if there are several
+pub fn find_data_file(base: &Path) -> Option<PathBuf> {
+    data_files(base).into_iter().next()
+}
+
+/// Reads a json, toml or yaml file, depending on its extension
+pub fn read_data_file(path: &Path) -> Result<Json> {
+    let contents = std::fs::read_to_string(path)
+        .chain_err(|| format!("Couldn't read data file {}", path.display()))?;
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    let res = match ext {
+        "toml" => toml::from_str(&contents).map_err(Error::from),
+        "yaml" | "yml" => serde_yaml::from_str(&contents).map_err(Error::from),
+        _ => serde_json::from_str(&contents).map_err(Error::from),
+    };
+    res.chain_err(|| format!("Couldn't parse data file {}", path.display()))
+}
+
+/// Parses a `key=value` assignment from the command line into a data layer.
+/// Dots in the key make nested objects, so `colors.bg=#000` gives
+/// `{"colors": {"bg": "#000"}}`. Values that are valid json (numbers,
+/// booleans, arrays...) are used as such, anything else is a string.
+pub fn parse_assignment(s: &str) -> Result<Json> {
+    let (key, value) = match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => (key, value),
+        _ => bail!("Expected key=value, got {}", s),
+    };
+    let value = serde_json::from_str(value).unwrap_or_else(|_| Json::String(value.to_owned()));
+    Ok(key
+        .rsplit('.')
+        .fold(value, |inner, part| json!({ part: inner })))
+}
+
+/// Merges `layer` into `base`, with `layer` taking precedence.
+///
+/// Objects are merged key by key, recursively. A `null` in `layer` deletes
+/// the key from `base`. Any other value replaces what was in `base`, arrays
+/// included, unless the object holding them lists another strategy under
+/// `_merge`, e.g. `{"paths": ["~/bin"], "_merge": {"paths": "append"}}`.
+/// Array strategies are `replace`, `append`, `prepend` and `union`
+/// (append only items not already present).
+pub fn deep_merge(base: &mut Json, layer: Json) -> Result<()> {
+    match layer {
+        Json::Object(layer) => {
+            if !base.is_object() {
+                *base = Json::Object(Map::new());
+            }
+            match base {
+                Json::Object(ref mut base) => merge_objects(base, layer),
+                _ => unreachable!(),
+            }
+        }
+        layer => {
+            *base = layer;
+            Ok(())
+        }
+    }
+}
+
+fn merge_objects(base: &mut Map<String, Json>, mut layer: Map<String, Json>) -> Result<()> {
+    let strategies = match layer.remove("_merge") {
+        None => Map::new(),
+        Some(Json::Object(s)) => s,
+        Some(_) => bail!("_merge must be an object mapping keys to merge strategies"),
+    };
+
+    for (key, value) in layer {
+        if value.is_null() {
+            base.remove(&key);
+            continue;
+        }
+        let strategy = match strategies.get(&key) {
+            None => "replace",
+            Some(Json::String(s)) => s.as_str(),
+            Some(_) => bail!("Merge strategy of {} must be a string", key),
+        };
+        match (base.get_mut(&key), value) {
+            (Some(Json::Array(ref mut old)), Json::Array(new)) => {
+                merge_arrays(old, new, strategy).chain_err(|| format!("Could not merge {}", key))?
+            }
+            (Some(old), value) => {
+                deep_merge(old, value).chain_err(|| format!("Could not merge {}", key))?
+            }
+            (None, value) => {
+                // merged into nothing, so that nested nulls and _merge keys are dropped
+                let mut new = Json::Null;
+                deep_merge(&mut new, value).chain_err(|| format!("Could not merge {}", key))?;
+                base.insert(key, new);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn merge_arrays(base: &mut Vec<Json>, layer: Vec<Json>, strategy: &str) -> Result<()> {
+    match strategy {
+        "replace" => *base = layer,
+        "append" => base.extend(layer),
+        "prepend" => {
+            let old = std::mem::replace(base, layer);
+            base.extend(old);
+        }
+        "union" => {
+            for item in layer {
+                if !base.contains(&item) {
+                    base.push(item);
+                }
+            }
+        }
+        _ => bail!("Unknown merge strategy {}", strategy),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_assignment() {
+        assert_eq!(json!({"a": "b"}), parse_assignment("a=b").unwrap());
+        assert_eq!(json!({"a": {"b": 1}}), parse_assignment("a.b=1").unwrap());
+        assert_eq!(json!({"a": [true]}), parse_assignment("a=[true]").unwrap());
+        assert_eq!(json!({"a": "x=y"}), parse_assignment("a=x=y").unwrap());
+        assert_eq!(json!({"a": ""}), parse_assignment("a=").unwrap());
+        assert!(parse_assignment("novalue").is_err());
+        assert!(parse_assignment("=value").is_err());
+    }
+}
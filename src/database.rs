@@ -5,6 +5,7 @@ use std::path::PathBuf;
 
 use crate::errors::*;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::io::prelude::*;
 use std::time::SystemTime;
@@ -16,17 +17,80 @@ use std::hash::Hash;
 
 // For now the "Database" is gonna just be a json file
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Entry {
     pub template_path: PathBuf,
     pub config_path: Option<PathBuf>,
     pub destination: PathBuf,
     pub last_updated: SystemTime,
+    /// Output of the last render, used as the base of three-way merges
+    #[serde(default)]
+    pub last_rendered: Option<String>,
+    /// Content hash of the last rendered output
+    #[serde(default)]
+    pub hash: Option<String>,
+    /// Modification time of the destination right after it was written.
+    /// Only a hint: if it still matches, the contents are not hashed again.
+    #[serde(default)]
+    pub mtime: Option<SystemTime>,
+}
+
+impl Entry {
+    pub fn new(template_path: PathBuf, destination: PathBuf) -> Self {
+        Self {
+            template_path,
+            config_path: None,
+            destination,
+            last_updated: SystemTime::now(),
+            last_rendered: None,
+            hash: None,
+            mtime: None,
+        }
+    }
+}
+
+/// Hex encoded SHA-256 of `contents`
+pub fn content_hash(contents: &str) -> String {
+    Sha256::digest(contents.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct DatabaseData {
+    /// Profile selected when rotfiles last ran
+    #[serde(default)]
+    profile: Option<String>,
+    entries: HashMap<PathBuf, Entry>,
+}
+
+// Early versions stored nothing but the entries
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredData {
+    Current(DatabaseData),
+    Legacy(HashMap<PathBuf, Entry>),
+}
+
+impl From<StoredData> for DatabaseData {
+    fn from(data: StoredData) -> Self {
+        match data {
+            StoredData::Current(data) => data,
+            StoredData::Legacy(entries) => DatabaseData {
+                profile: None,
+                entries,
+            },
+        }
+    }
 }
 
 pub struct Database {
     fname: PathBuf,
     data: HashMap<PathBuf, Entry>,
+    profile: Option<String>,
+    read_only: bool,
+    dirty: bool,
 }
 
 impl Database {
@@ -34,9 +98,41 @@ impl Database {
         let fname = cfg.backup_path.join("database.json");
         Database::ensure_exists(cfg)?;
         let file = File::open(&fname).chain_err(|| "Couldn't open database file")?;
-        let data = serde_json::from_reader(file).chain_err(|| "Error decoding")?;
+        let stored: StoredData = serde_json::from_reader(file).chain_err(|| "Error decoding")?;
+        let data = DatabaseData::from(stored);
 
-        Ok(Self { fname, data })
+        Ok(Self {
+            fname,
+            data: data.entries,
+            profile: data.profile,
+            read_only: false,
+            dirty: false,
+        })
+    }
+
+    /// Opens the database without ever creating or writing database.json.
+    /// A missing file is treated as an empty database.
+    pub fn connect_read_only(cfg: &crate::config::Config) -> Result<Self> {
+        let fname = cfg.backup_path.join("database.json");
+        let data = if fname.exists() {
+            let file = File::open(&fname).chain_err(|| "Couldn't open database file")?;
+            let stored: StoredData =
+                serde_json::from_reader(file).chain_err(|| "Error decoding")?;
+            DatabaseData::from(stored)
+        } else {
+            DatabaseData {
+                profile: None,
+                entries: HashMap::new(),
+            }
+        };
+
+        Ok(Self {
+            fname,
+            data: data.entries,
+            profile: data.profile,
+            read_only: true,
+            dirty: false,
+        })
     }
 
     fn ensure_exists(cfg: &crate::config::Config) -> Result<()> {
@@ -51,15 +147,45 @@ impl Database {
     }
 
     pub fn commit(&self) -> Result<()> {
+        if self.read_only {
+            bail!("Database was opened read-only");
+        }
         let file = File::create(&self.fname).chain_err(|| "Couldn't overwrite database file")?;
-        serde_json::to_writer_pretty(file, &self.data).chain_err(|| "Error writing data")?;
+        let data = json!({
+            "profile": self.profile,
+            "entries": self.data,
+        });
+        serde_json::to_writer_pretty(file, &data).chain_err(|| "Error writing data")?;
 
         Ok(())
     }
 
+    pub fn path(&self) -> &PathBuf {
+        &self.fname
+    }
+
+    pub fn profile(&self) -> Option<&str> {
+        self.profile.as_deref()
+    }
+
+    pub fn set_profile(&mut self, profile: Option<String>) {
+        self.dirty |= self.profile != profile;
+        self.profile = profile;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only
+    }
+
+    /// Whether the in-memory data differs from what was loaded
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
     pub fn touch(&mut self, path: &PathBuf) -> Result<()> {
         let res = match self.data.get_mut(path) {
             Some(ref mut e) => {
+                self.dirty = true;
                 e.last_updated = SystemTime::now();
                 Ok(())
             }
@@ -69,6 +195,25 @@ impl Database {
         res
     }
 
+    /// Stores the output last rendered for `path` and marks it as updated.
+    /// `mtime` should only be given if the destination holds exactly `rendered`.
+    pub fn record_render(
+        &mut self,
+        path: &PathBuf,
+        rendered: String,
+        mtime: Option<SystemTime>,
+    ) -> Result<()> {
+        match self.data.get_mut(path) {
+            Some(e) => {
+                e.hash = Some(content_hash(&rendered));
+                e.last_rendered = Some(rendered);
+                e.mtime = mtime;
+            }
+            None => bail!("Rendered file is not in database"),
+        }
+        self.touch(path)
+    }
+
     pub fn log_contents(&self) {
         let mut res = String::new();
         for (k, v) in self.data.iter() {
@@ -87,20 +232,29 @@ impl Database {
 
     pub fn add_entry(&mut self, e: Entry) -> Option<Entry> {
         let res = self.data.insert(e.destination.clone(), e);
+        self.dirty = true;
         self.log_contents();
         res
     }
 
     pub fn rm_key(&mut self, path: &PathBuf) {
-        self.data.remove(path);
+        self.dirty |= self.data.remove(path).is_some();
         self.log_contents();
     }
 
     pub fn rm_entry(&mut self, e: Entry) {
-        self.data.remove(&e.destination);
+        self.dirty |= self.data.remove(&e.destination).is_some();
         self.log_contents();
     }
 
+    pub fn get(&self, path: &PathBuf) -> Option<&Entry> {
+        self.data.get(path)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = &Entry> {
+        self.data.values()
+    }
+
     pub fn last_updated(&self, path: &PathBuf) -> Option<SystemTime> {
         self.data.get(path).map(|e| e.last_updated)
     }
@@ -115,10 +269,64 @@ impl Database {
 #[cfg(not(test))]
 impl Drop for Database {
     fn drop(&mut self) {
+        if self.read_only {
+            return;
+        }
         debug!("Commiting to database on drop");
         self.commit().unwrap();
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_content_hash() {
+        assert_eq!(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            content_hash("")
+        );
+        assert_ne!(content_hash("a"), content_hash("b"));
+    }
+
+    #[test]
+    fn test_legacy_entry() {
+        let json = r#"{
+            "template_path": "/home/user/dotfiles/testfile",
+            "config_path": null,
+            "destination": "/home/user/.testfile",
+            "last_updated": {"secs_since_epoch": 1582302524, "nanos_since_epoch": 0}
+        }"#;
+        let entry: Entry = serde_json::from_str(json).unwrap();
+        assert_eq!(None, entry.hash);
+        assert_eq!(None, entry.mtime);
+    }
+
+    #[test]
+    fn test_legacy_database() {
+        let tmp = tempfile::TempDir::new().unwrap();
+        let cfg = crate::config::Config::with_home(tmp.path());
+        std::fs::create_dir_all(&cfg.backup_path).unwrap();
+        let fname = cfg.backup_path.join("database.json");
+        let legacy = r#"{
+            "/home/user/.testfile": {
+                "template_path": "/home/user/dotfiles/testfile",
+                "config_path": null,
+                "destination": "/home/user/.testfile",
+                "last_updated": {"secs_since_epoch": 1582302524, "nanos_since_epoch": 0}
+            }
+        }"#;
+        std::fs::write(&fname, legacy).unwrap();
+
+        let mut db = Database::connect(&cfg).unwrap();
+        assert_eq!(1, db.entries().count());
+        assert_eq!(None, db.profile());
+        db.set_profile(Some("work".to_owned()));
+        db.commit().unwrap();
+
+        let db = Database::connect_read_only(&cfg).unwrap();
+        assert_eq!(1, db.entries().count());
+        assert_eq!(Some("work"), db.profile());
+    }
+}
@@ -0,0 +1,140 @@
+use std::fs;
+use std::path::Path;
+
+use serde_json::{Map, Value as Json};
+
+/// Facts about the machine rotfiles runs on, exposed to templates as `facts`
+pub fn gather(home_path: &Path) -> Json {
+    let os = fs::read_to_string("/etc/os-release")
+        .or_else(|_| fs::read_to_string("/usr/lib/os-release"))
+        .map(|s| parse_os_release(&s))
+        .unwrap_or_else(|_| json!({}));
+    let memory = fs::read_to_string("/proc/meminfo")
+        .ok()
+        .and_then(|s| parse_meminfo(&s));
+    let cpus = std::thread::available_parallelism()
+        .map(|n| n.get())
+        .unwrap_or(1);
+
+    json!({
+        "hostname": hostname(),
+        "os": os,
+        "family": std::env::consts::OS,
+        "kernel": kernel(),
+        "arch": std::env::consts::ARCH,
+        "user": username(),
+        "home": home_path,
+        "cpus": cpus,
+        "memory": memory,
+    })
+}
+
+/// Name of this machine. Tries the kernel, /etc/hostname, the `hostname`
+/// command and $HOSTNAME, and falls back to "localhost".
+pub fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .ok()
+        .and_then(non_empty)
+        .or_else(|| fs::read_to_string("/etc/hostname").ok().and_then(non_empty))
+        .or_else(|| command_output("hostname").and_then(non_empty))
+        .or_else(|| std::env::var("HOSTNAME").ok().and_then(non_empty))
+        .unwrap_or_else(|| "localhost".to_owned())
+}
+
+fn non_empty(s: String) -> Option<String> {
+    match s.trim() {
+        "" => None,
+        trimmed => Some(trimmed.to_owned()),
+    }
+}
+
+fn kernel() -> Option<String> {
+    fs::read_to_string("/proc/sys/kernel/osrelease")
+        .ok()
+        .or_else(|| command_output("uname -r"))
+        .map(|s| s.trim().to_owned())
+}
+
+fn username() -> Option<String> {
+    std::env::var("USER")
+        .or_else(|_| std::env::var("LOGNAME"))
+        .ok()
+        .or_else(|| command_output("id -un"))
+        .map(|s| s.trim().to_owned())
+}
+
+fn command_output(cmd: &str) -> Option<String> {
+    crate::commands::run(cmd, std::time::Duration::from_secs(1)).ok()
+}
+
+/// Picks the commonly used keys of an os-release file, with lowercase names
+fn parse_os_release(contents: &str) -> Json {
+    let mut res = Map::new();
+    for line in contents.lines() {
+        let (key, value) = match line.split_once('=') {
+            Some(kv) => kv,
+            None => continue,
+        };
+        let name = match key.trim() {
+            "ID" => "id",
+            "ID_LIKE" => "id_like",
+            "NAME" => "name",
+            "PRETTY_NAME" => "pretty_name",
+            "VERSION_ID" => "version",
+            "VERSION_CODENAME" => "codename",
+            _ => continue,
+        };
+        let value = value.trim().trim_matches(|c| c == '"' || c == '\'');
+        res.insert(name.to_owned(), json!(value));
+    }
+    Json::Object(res)
+}
+
+/// Total memory in bytes
+fn parse_meminfo(contents: &str) -> Option<u64> {
+    let line = contents.lines().find(|l| l.starts_with("MemTotal:"))?;
+    let kb: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kb * 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_os_release() {
+        let contents = r#"PRETTY_NAME="Debian GNU/Linux 12 (bookworm)"
+NAME="Debian GNU/Linux"
+VERSION_ID="12"
+VERSION_CODENAME=bookworm
+ID=debian
+HOME_URL="https://www.debian.org/"
+"#;
+        assert_eq!(
+            json!({
+                "pretty_name": "Debian GNU/Linux 12 (bookworm)",
+                "name": "Debian GNU/Linux",
+                "version": "12",
+                "codename": "bookworm",
+                "id": "debian",
+            }),
+            parse_os_release(contents)
+        );
+    }
+
+    #[test]
+    fn test_parse_meminfo() {
+        let contents = "MemTotal:       16318888 kB\nMemFree:         1234567 kB\n";
+        assert_eq!(Some(16318888 * 1024), parse_meminfo(contents));
+        assert_eq!(None, parse_meminfo("MemFree: 1 kB\n"));
+    }
+
+    #[test]
+    fn test_gather() {
+        let facts = gather(Path::new("/home/user"));
+        assert!(!facts["hostname"].as_str().unwrap().is_empty());
+        assert_eq!(std::env::consts::ARCH, facts["arch"]);
+        assert_eq!("/home/user", facts["home"]);
+        assert!(facts["cpus"].as_u64().unwrap() >= 1);
+    }
+}
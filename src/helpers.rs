@@ -0,0 +1,328 @@
+use handlebars::{Context, Handlebars, Helper, HelperDef, RenderContext, RenderError, ScopedJson};
+use serde_json::Value as Json;
+use std::path::Path;
+
+type HelperFn = fn(&[Json]) -> Result<Json, RenderError>;
+
+/// Helper computing a value from its parameters, so it can be used both
+/// directly (`{{upper name}}`) and as a subexpression (`{{#if (eq a b)}}`)
+struct ValueHelper {
+    name: &'static str,
+    f: HelperFn,
+}
+
+impl HelperDef for ValueHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg>,
+    ) -> Result<Option<ScopedJson<'reg, 'rc>>, RenderError> {
+        let params: Vec<Json> = h.params().iter().map(|p| p.value().clone()).collect();
+        (self.f)(&params)
+            .map(|v| Some(ScopedJson::Derived(v)))
+            .map_err(|e| RenderError::new(format!("`{}` helper: {}", self.name, e.desc)))
+    }
+}
+
+/// Registers the helpers available in every template
+pub fn register(registry: &mut Handlebars) {
+    let helpers: [(&'static str, HelperFn); 16] = [
+        ("eq", eq),
+        ("ne", ne),
+        ("and", and),
+        ("or", or),
+        ("not", not),
+        ("default", default),
+        ("upper", upper),
+        ("lower", lower),
+        ("replace", replace),
+        ("join", join),
+        ("env", env),
+        ("path_exists", path_exists),
+        ("contains", contains),
+        ("lighten", lighten),
+        ("darken", darken),
+        ("hex_to_rgb", hex_to_rgb),
+    ];
+    for (name, f) in helpers.iter() {
+        registry.register_helper(name, Box::new(ValueHelper { name, f: *f }));
+    }
+}
+
+/// Same rules as `{{#if}}`: null, false, 0, "" and empty arrays and objects are false
+fn truthy(v: &Json) -> bool {
+    match v {
+        Json::Null => false,
+        Json::Bool(b) => *b,
+        Json::Number(n) => n.as_f64() != Some(0.0),
+        Json::String(s) => !s.is_empty(),
+        Json::Array(a) => !a.is_empty(),
+        Json::Object(o) => !o.is_empty(),
+    }
+}
+
+/// Text a value renders to: strings as they are, null as nothing, the rest as json
+fn text(v: &Json) -> String {
+    match v {
+        Json::Null => String::new(),
+        Json::String(s) => s.clone(),
+        v => v.to_string(),
+    }
+}
+
+fn param(params: &[Json], i: usize) -> Result<&Json, RenderError> {
+    params
+        .get(i)
+        .ok_or_else(|| RenderError::new(format!("missing parameter {}", i + 1)))
+}
+
+fn str_param(params: &[Json], i: usize) -> Result<&str, RenderError> {
+    param(params, i)?
+        .as_str()
+        .ok_or_else(|| RenderError::new(format!("parameter {} must be a string", i + 1)))
+}
+
+fn eq(params: &[Json]) -> Result<Json, RenderError> {
+    Ok(json!(param(params, 0)? == param(params, 1)?))
+}
+
+fn ne(params: &[Json]) -> Result<Json, RenderError> {
+    Ok(json!(param(params, 0)? != param(params, 1)?))
+}
+
+fn and(params: &[Json]) -> Result<Json, RenderError> {
+    param(params, 0)?;
+    Ok(json!(params.iter().all(truthy)))
+}
+
+fn or(params: &[Json]) -> Result<Json, RenderError> {
+    param(params, 0)?;
+    Ok(json!(params.iter().any(truthy)))
+}
+
+fn not(params: &[Json]) -> Result<Json, RenderError> {
+    Ok(json!(!truthy(param(params, 0)?)))
+}
+
+/// `{{default value fallback}}`: `value` unless it is false-ish
+fn default(params: &[Json]) -> Result<Json, RenderError> {
+    let fallback = param(params, 1)?;
+    match params[0] {
+        ref v if truthy(v) => Ok(v.clone()),
+        _ => Ok(fallback.clone()),
+    }
+}
+
+fn upper(params: &[Json]) -> Result<Json, RenderError> {
+    Ok(json!(text(param(params, 0)?).to_uppercase()))
+}
+
+fn lower(params: &[Json]) -> Result<Json, RenderError> {
+    Ok(json!(text(param(params, 0)?).to_lowercase()))
+}
+
+/// `{{replace text from to}}`
+fn replace(params: &[Json]) -> Result<Json, RenderError> {
+    let s = text(param(params, 0)?);
+    Ok(json!(
+        s.replace(str_param(params, 1)?, str_param(params, 2)?)
+    ))
+}
+
+/// `{{join list separator}}`
+fn join(params: &[Json]) -> Result<Json, RenderError> {
+    let list = param(params, 0)?
+        .as_array()
+        .ok_or_else(|| RenderError::new("parameter 1 must be an array"))?;
+    let sep = str_param(params, 1)?;
+    let items: Vec<String> = list.iter().map(text).collect();
+    Ok(json!(items.join(sep)))
+}
+
+/// `{{env "NAME"}}` or `{{env "NAME" "fallback"}}`
+fn env(params: &[Json]) -> Result<Json, RenderError> {
+    let name = str_param(params, 0)?;
+    match std::env::var(name) {
+        Ok(v) => Ok(json!(v)),
+        Err(_) => Ok(params.get(1).cloned().unwrap_or(Json::Null)),
+    }
+}
+
+/// `{{path_exists "~/.cargo"}}`, `~` and environment variables are expanded
+fn path_exists(params: &[Json]) -> Result<Json, RenderError> {
+    let path = str_param(params, 0)?;
+    let expanded = shellexpand::full(path).map_err(|e| RenderError::new(e.to_string()))?;
+    Ok(json!(Path::new(expanded.as_ref()).exists()))
+}
+
+/// Substring of a string, element of an array or key of an object
+fn contains(params: &[Json]) -> Result<Json, RenderError> {
+    let needle = param(params, 1)?;
+    let res = match param(params, 0)? {
+        Json::String(s) => s.contains(&text(needle)),
+        Json::Array(a) => a.contains(needle),
+        Json::Object(o) => o.contains_key(&text(needle)),
+        _ => false,
+    };
+    Ok(json!(res))
+}
+
+fn parse_color(s: &str) -> Result<[u8; 3], RenderError> {
+    let hex = s.trim_start_matches('#');
+    let digits: Vec<u8> = match hex.len() {
+        3 => hex
+            .chars()
+            .flat_map(|c| vec![c, c])
+            .collect::<String>()
+            .into_bytes(),
+        6 => hex.as_bytes().to_vec(),
+        _ => return Err(RenderError::new(format!("{} is not a #rrggbb color", s))),
+    };
+    let mut res = [0; 3];
+    for (i, c) in res.iter_mut().enumerate() {
+        let pair = std::str::from_utf8(&digits[2 * i..2 * i + 2]).unwrap_or("");
+        *c = u8::from_str_radix(pair, 16)
+            .map_err(|_| RenderError::new(format!("{} is not a #rrggbb color", s)))?;
+    }
+    Ok(res)
+}
+
+/// Moves every channel of a color `amount` percent of the way to `target`
+fn mix(params: &[Json], target: f64) -> Result<Json, RenderError> {
+    let color = str_param(params, 0)?;
+    let amount = param(params, 1)?
+        .as_f64()
+        .ok_or_else(|| RenderError::new("parameter 2 must be a percentage"))?;
+    let amount = amount.clamp(0.0, 100.0) / 100.0;
+    let rgb = parse_color(color)?;
+    let hex: String = rgb
+        .iter()
+        .map(|&c| {
+            let c = f64::from(c);
+            format!("{:02x}", (c + (target - c) * amount).round() as u8)
+        })
+        .collect();
+    let prefix = if color.starts_with('#') { "#" } else { "" };
+    Ok(json!(format!("{}{}", prefix, hex)))
+}
+
+/// `{{lighten "#336699" 20}}` mixes the color with 20% white
+fn lighten(params: &[Json]) -> Result<Json, RenderError> {
+    mix(params, 255.0)
+}
+
+/// `{{darken "#336699" 20}}` mixes the color with 20% black
+fn darken(params: &[Json]) -> Result<Json, RenderError> {
+    mix(params, 0.0)
+}
+
+/// `{{hex_to_rgb "#ff8000"}}` gives "255, 128, 0"
+fn hex_to_rgb(params: &[Json]) -> Result<Json, RenderError> {
+    let [r, g, b] = parse_color(str_param(params, 0)?)?;
+    Ok(json!(format!("{}, {}, {}", r, g, b)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, data: Json) -> Result<String, String> {
+        let mut registry = Handlebars::new();
+        register(&mut registry);
+        registry
+            .render_template(template, &data)
+            .map_err(|e| e.to_string())
+    }
+
+    fn check(expected: &str, template: &str) {
+        let data = json!({
+            "name": "Rot Files",
+            "empty": "",
+            "list": ["a", "b", "c"],
+            "map": {"key": 1},
+            "yes": true,
+            "no": false,
+            "zero": 0,
+        });
+        assert_eq!(
+            Ok(expected.to_owned()),
+            render(template, data),
+            "{}",
+            template
+        );
+    }
+
+    #[test]
+    fn test_comparisons() {
+        check("true", "{{eq name \"Rot Files\"}}");
+        check("false", "{{eq zero 1}}");
+        check("true", "{{ne zero 1}}");
+        check("yes", "{{#if (eq map.key 1)}}yes{{/if}}");
+        assert!(render("{{eq name}}", json!({})).is_err());
+    }
+
+    #[test]
+    fn test_logic() {
+        check("true", "{{and yes name list}}");
+        check("false", "{{and yes name zero}}");
+        check("true", "{{or no empty zero map}}");
+        check("false", "{{or no empty missing}}");
+        check("true", "{{not no}}");
+        check("false", "{{not list}}");
+        check("both", "{{#if (and yes (not no))}}both{{/if}}");
+    }
+
+    #[test]
+    fn test_default() {
+        check("Rot Files", "{{default name \"x\"}}");
+        check("x", "{{default empty \"x\"}}");
+        check("fallback", "{{default missing \"fallback\"}}");
+    }
+
+    #[test]
+    fn test_strings() {
+        check("ROT FILES", "{{upper name}}");
+        check("rot files", "{{lower name}}");
+        check("Rot-Files", "{{replace name \" \" \"-\"}}");
+        check("a, b, c", "{{join list \", \"}}");
+        assert!(render("{{join name \",\"}}", json!({"name": "x"})).is_err());
+    }
+
+    #[test]
+    fn test_env() {
+        std::env::set_var("ROTFILES_HELPER_TEST", "value");
+        check("value", "{{env \"ROTFILES_HELPER_TEST\"}}");
+        check("", "{{env \"ROTFILES_HELPER_UNSET\"}}");
+        check("else", "{{env \"ROTFILES_HELPER_UNSET\" \"else\"}}");
+    }
+
+    #[test]
+    fn test_path_exists() {
+        check("true", "{{path_exists \"/\"}}");
+        check("true", "{{path_exists \"~\"}}");
+        check("false", "{{path_exists \"/surely/not/here\"}}");
+    }
+
+    #[test]
+    fn test_contains() {
+        check("true", "{{contains name \"Files\"}}");
+        check("false", "{{contains name \"files\"}}");
+        check("true", "{{contains list \"b\"}}");
+        check("false", "{{contains list \"d\"}}");
+        check("true", "{{contains map \"key\"}}");
+    }
+
+    #[test]
+    fn test_colors() {
+        check("#ffffff", "{{lighten \"#336699\" 100}}");
+        check("#5c85ad", "{{lighten \"#336699\" 20}}");
+        check("#29527a", "{{darken \"#336699\" 20}}");
+        check("000000", "{{darken \"fff\" 100}}");
+        check("51, 102, 153", "{{hex_to_rgb \"#336699\"}}");
+        check("255, 255, 255", "{{hex_to_rgb \"#fff\"}}");
+        assert!(render("{{hex_to_rgb \"#12345\"}}", json!({})).is_err());
+        assert!(render("{{lighten \"#zzzzzz\" 10}}", json!({})).is_err());
+    }
+}
@@ -1,3 +1,10 @@
+// error_chain and serde_derive expand to code that trips newer rustc/clippy lints
+#![allow(
+    deprecated,
+    unexpected_cfgs,
+    non_local_definitions,
+    clippy::result_large_err
+)]
 extern crate handlebars;
 #[macro_use]
 extern crate serde_json;
@@ -10,7 +17,6 @@ extern crate chrono;
 extern crate glob;
 extern crate pretty_env_logger;
 extern crate subprocess;
-use handlebars::Handlebars;
 use std::path::{Path, PathBuf};
 
 use chrono::prelude::*;
@@ -18,14 +24,30 @@ use serde_json::Value as Json;
 use std::ffi::OsString;
 use std::fs::File;
 use std::io::prelude::*;
+use std::cell::OnceCell;
+use std::io::IsTerminal;
 use std::time::SystemTime;
 
 use path_abs::PathAbs;
 
+pub mod backup;
+pub mod commands;
 pub mod config;
+pub mod data;
 pub mod database;
-
+pub mod facts;
+pub mod helpers;
+pub mod merge;
+pub mod plan;
+pub mod render;
+pub mod status;
+
+use self::backup::{BackupIndex, BackupRecord, Change, Reason, Transaction};
 use self::database::{Database, Entry};
+use self::merge::sanitize;
+use self::plan::Action;
+use self::render::Renderer;
+use self::status::FileStatus;
 
 pub mod errors {
     error_chain! {
@@ -42,29 +64,65 @@ pub mod errors {
 
             FileNewerThanTemplate(fname: String) {
                 description("File has been changed from under its template"),
-                display("File {} is newer than its template", fname),
+                display("File {} is newer than its template (use `rotfiles pull` to keep its changes)", fname),
             }
             
             NotInDatabaseError {
                 description("File not in database")
             }
 
+            UndefinedVariable(template: String, line: usize, variable: String) {
+                description("Template uses an undefined variable"),
+                display("Undefined variable {} in template {} line {}", variable, template, line),
+            }
+
         }
         foreign_links {
             Io(std::io::Error);
             SerdeJson(serde_json::Error);
             HandlebarsTemplate(handlebars::TemplateFileError);
             HandlebarsRender(handlebars::RenderError);
+            Toml(toml::de::Error);
+            Yaml(serde_yaml::Error);
         }
     }
 }
 
 use errors::*;
 
+/// Directory under `dot_path` holding partials shared by all templates
+const PARTIALS_DIR: &str = "_partials";
+
+/// How the dotfiles directory is scanned: hidden files (editor swap files,
+/// `.git`) are left out
+const GLOB_OPTIONS: glob::MatchOptions = glob::MatchOptions {
+    case_sensitive: true,
+    require_literal_separator: false,
+    require_literal_leading_dot: true,
+};
+
+/// What `update --bootstrap` does with a destination that already exists
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bootstrap {
+    /// Keep the file and start managing it
+    Adopt,
+    /// Replace the file with the rendered template, after a backup
+    Overwrite,
+    /// Leave the file alone and unmanaged
+    Skip,
+}
+
 pub struct App {
     pub cfg: config::Config,
 
     db: Database,
+    backups: BackupIndex,
+    // Some in dry-run mode: changes are recorded here instead of being made
+    plan: Option<Vec<Action>>,
+    // env and commands data, computed on first render
+    runtime_data: OnceCell<Json>,
+    // fail on undefined variables in templates that don't set `_strict`
+    strict: bool,
     #[cfg(test)]
     // ensure directory is dropped and cleaned after exit
     _tempdir: Option<tempfile::TempDir>,
@@ -72,7 +130,7 @@ pub struct App {
 
 impl App {
     #[cfg(test)]
-    fn new_test<'a>() -> Result<Self> {
+    fn new_test() -> Result<Self> {
         let pseudo_home_dir =
             tempfile::TempDir::new().chain_err(|| "Can't create temp pseudo home dir")?;
         let home_path = pseudo_home_dir.path().to_owned();
@@ -80,23 +138,29 @@ impl App {
         let backup_path = home_path.join(".local/share/rotfiles/backup");
 
         let cfg = config::Config::new(&home_path, &dot_path, &backup_path);
-        let res;
-
-        if let Ok(_) = std::env::var("ROTFILES_TEST_NO_CLEANUP") {
+        let mut res = if std::env::var("ROTFILES_TEST_NO_CLEANUP").is_ok() {
             debug!("Temp dir will not be deleted");
             std::mem::forget(pseudo_home_dir);
-            res = Self {
+            Self {
                 cfg: cfg.clone(),
                 db: Database::connect(&cfg).chain_err(|| "Could not connect to database")?,
+                backups: BackupIndex::connect(&cfg).chain_err(|| "Could not open backup index")?,
+                plan: None,
+                runtime_data: OnceCell::new(),
+                strict: false,
                 _tempdir: None,
-            };
+            }
         } else {
-            res = Self {
+            Self {
                 cfg: cfg.clone(),
                 db: Database::connect(&cfg).chain_err(|| "Could not connect to database")?,
+                backups: BackupIndex::connect(&cfg).chain_err(|| "Could not open backup index")?,
+                plan: None,
+                runtime_data: OnceCell::new(),
+                strict: false,
                 _tempdir: Some(pseudo_home_dir),
-            };
-        }
+            }
+        };
 
         res.ensure_workpath_exists()
             .chain_err(|| "Could not create workpaths")?;
@@ -105,9 +169,13 @@ impl App {
     }
 
     pub fn from_config(cfg: config::Config) -> Result<Self> {
-        let res = Self {
+        let mut res = Self {
             cfg: cfg.clone(),
             db: Database::connect(&cfg).chain_err(|| "Could not connect to database")?,
+            backups: BackupIndex::connect(&cfg).chain_err(|| "Could not open backup index")?,
+            plan: None,
+            runtime_data: OnceCell::new(),
+            strict: false,
             #[cfg(test)]
             _tempdir: None,
         };
@@ -116,44 +184,211 @@ impl App {
         Ok(res)
     }
 
-    fn ensure_workpath_exists(&self) -> Result<()> {
-        let path = &self.cfg.backup_path;
+    /// Instantiates App without creating any directories and with a database
+    /// that is never written back. Used by commands that only inspect state.
+    pub fn from_config_read_only(cfg: config::Config) -> Result<Self> {
+        Ok(Self {
+            cfg: cfg.clone(),
+            db: Database::connect_read_only(&cfg).chain_err(|| "Could not open database")?,
+            backups: BackupIndex::connect_read_only(&cfg)
+                .chain_err(|| "Could not open backup index")?,
+            plan: None,
+            runtime_data: OnceCell::new(),
+            strict: false,
+            #[cfg(test)]
+            _tempdir: None,
+        })
+    }
+
+    /// Instantiates App that records every change it would make instead of
+    /// making it. The recorded actions are available through `planned_actions`.
+    pub fn from_config_dry_run(cfg: config::Config) -> Result<Self> {
+        let mut res = Self::from_config_read_only(cfg)?;
+        res.plan = Some(Vec::new());
+        res.ensure_workpath_exists()
+            .chain_err(|| "Could not plan workpaths")?;
+        Ok(res)
+    }
+
+    /// Sets rotfiles up from scratch: creates the directories `cfg` points to,
+    /// the global dotconfig.json and finally writes `cfg` to `config_path`
+    pub fn init(cfg: config::Config, config_path: &Path, dry_run: bool) -> Result<Self> {
+        if config_path.exists() {
+            bail!("Config {} already exists", config_path.display());
+        }
+        let mut res = if dry_run {
+            Self::from_config_dry_run(cfg)?
+        } else {
+            Self::from_config(cfg)?
+        };
+        let contents = res.cfg.to_string_for(config_path)?;
+        res.write_file(config_path, &contents)
+            .chain_err(|| format!("Could not write config {}", config_path.display()))?;
+        Ok(res)
+    }
+
+    /// Runs `git init` in the dotfiles directory, unless it already is a repository
+    pub fn init_repository(&mut self) -> Result<()> {
+        if self.cfg.dot_path.join(".git").exists() {
+            return Ok(());
+        }
+        let cmd = subprocess::Exec::cmd("git")
+            .arg("init")
+            .arg("--quiet")
+            .cwd(&self.cfg.dot_path);
+        if let Some(ref mut plan) = self.plan {
+            plan.push(Action::Run(format!(
+                "git init in {}",
+                self.cfg.dot_path.display()
+            )));
+            return Ok(());
+        }
+        match cmd.join().chain_err(|| "Could not run git")? {
+            subprocess::ExitStatus::Exited(0) => Ok(()),
+            _ => bail!("git init failed"),
+        }
+    }
+
+    /// Profile whose data layer is used, as remembered from previous runs
+    pub fn profile(&self) -> Option<&str> {
+        self.db.profile()
+    }
+
+    /// Makes templates fail to render if they use undefined variables,
+    /// unless they turn it off with `_strict: false` in their data
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    /// Selects a profile defined in the config and remembers it for later
+    /// runs. An empty name goes back to using no profile.
+    pub fn set_profile(&mut self, profile: &str) -> Result<()> {
+        if profile.is_empty() {
+            self.db.set_profile(None);
+            return Ok(());
+        }
+        if !self.cfg.profiles.contains_key(profile) {
+            let known: Vec<&str> = self.cfg.profiles.keys().map(|k| k.as_str()).collect();
+            bail!(
+                "Unknown profile {} (defined profiles: {})",
+                profile,
+                known.join(", ")
+            );
+        }
+        self.db.set_profile(Some(profile.to_owned()));
+        Ok(())
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.plan.is_some()
+    }
+
+    /// Actions recorded in dry-run mode, including the final database update
+    pub fn planned_actions(&self) -> Vec<Action> {
+        let mut res = self.plan.clone().unwrap_or_default();
+        if self.db.is_dirty() {
+            res.push(Action::CommitDatabase(self.db.path().clone()));
+        }
+        res
+    }
+
+    fn ensure_workpath_exists(&mut self) -> Result<()> {
+        let path = self.cfg.backup_path.clone();
         if !path.exists() {
-            std::fs::create_dir_all(path)?;
+            self.create_dir(&path)?;
         }
-        let path2 = &self.cfg.dot_path;
+        let path2 = self.cfg.dot_path.clone();
         if !path2.exists() {
-            std::fs::create_dir_all(path2)?;
+            self.create_dir(&path2)?;
         }
-        // ensure global config file exists
-        let path3 = &self.cfg.home_path.join(".config/rotfiles/dotconfig.json");
-        if !path3.exists() {
-            ensure_parent_exists(&path3)?;
-            let mut glob_file =
-                File::create(path3).chain_err(|| "Could not create global config file")?;
-            write!(glob_file, "{}", json!({get_hostname(): true}).to_string())
-                .chain_err(|| "Could not write global config")?;
+        // ensure global config file exists, in any of the data formats
+        let path3 = self.global_config_path();
+        if data::find_data_file(&path3.with_extension("")).is_none() {
+            self.write_file(&path3, &default_global_config().to_string())
+                .chain_err(|| "Could not create global config file")?;
         }
         Ok(())
     }
 
+    fn global_config_path(&self) -> PathBuf {
+        self.cfg.home_path.join(".config/rotfiles/dotconfig.json")
+    }
+
+    fn create_dir<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        match self.plan {
+            Some(ref mut plan) => plan.push(Action::CreateDir(path.as_ref().to_owned())),
+            None => std::fs::create_dir_all(&path).chain_err(|| {
+                format!("Could not create directory {}", path.as_ref().display())
+            })?,
+        }
+        Ok(())
+    }
+
+    /// Writes `contents` to `path`, creating parent directories as needed
+    fn write_file<P: AsRef<Path>>(&mut self, path: P, contents: &str) -> Result<()> {
+        if let Some(ref mut plan) = self.plan {
+            plan.push(Action::WriteFile {
+                path: path.as_ref().to_owned(),
+                bytes: contents.len(),
+            });
+            return Ok(());
+        }
+        ensure_parent_exists(&path).chain_err(|| "Could not create parent directories")?;
+        let mut file = File::create(&path)
+            .chain_err(|| format!("Could not create file {}", path.as_ref().display()))?;
+        write!(file, "{}", contents)
+            .chain_err(|| format!("Error writing file {}", path.as_ref().display()))?;
+        Ok(())
+    }
+
+    /// Copies `from` over `to`, creating parent directories as needed
+    fn copy_file<P: AsRef<Path>, U: AsRef<Path>>(&mut self, from: P, to: U) -> Result<()> {
+        if let Some(ref mut plan) = self.plan {
+            plan.push(Action::Copy {
+                from: from.as_ref().to_owned(),
+                to: to.as_ref().to_owned(),
+            });
+            return Ok(());
+        }
+        ensure_parent_exists(&to).chain_err(|| "Could not create parent directories")?;
+        std::fs::copy(&from, &to).chain_err(|| {
+            format!(
+                "Could not copy {} to {}",
+                from.as_ref().display(),
+                to.as_ref().display()
+            )
+        })?;
+        Ok(())
+    }
+
+    /// Modification time of a file rotfiles has just written, if it was really written
+    fn written_mtime<P: AsRef<Path>>(&self, path: P) -> Option<SystemTime> {
+        if self.is_dry_run() {
+            None
+        } else {
+            file_mtime(path).ok()
+        }
+    }
+
+    fn remove_path<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        match self.plan {
+            Some(ref mut plan) => plan.push(Action::RemoveFile(path.as_ref().to_owned())),
+            None => std::fs::remove_file(&path)
+                .chain_err(|| format!("Could not remove file {}", path.as_ref().display()))?,
+        }
+        Ok(())
+    }
+
+    /// Fails with `FileNewerThanTemplate` if the destination was changed since
+    /// rotfiles last wrote it
     fn ensure_template_newer_than_file<P: AsRef<Path>>(&self, dpath: P) -> Result<()> {
         let dotfile_path = dpath.as_ref();
-        let file_metadata = dotfile_path
-            .metadata()
-            .chain_err(|| "Can't access file's metadata")?;
-        let file_mtime = file_metadata
-            .modified()
-            .chain_err(|| "Can't access modification time")?;
-
-        let database_mtime = self
+        let entry = self
             .db
-            .last_updated(&dotfile_path.to_owned())
-            .chain_err(|| "Path not in database")?;
-        debug!("Database modification time: {:?}", database_mtime);
-        debug!("Dotfile modification time: {:?}", file_mtime);
+            .get(&dotfile_path.to_owned())
+            .ok_or(ErrorKind::NotInDatabaseError)?;
 
-        if file_mtime > database_mtime {
+        if self.destination_modified(entry)? {
             bail!(ErrorKind::FileNewerThanTemplate(
                 dotfile_path.to_string_lossy().into()
             ));
@@ -161,50 +396,309 @@ impl App {
         Ok(())
     }
 
-    fn get_template_config_data<P: AsRef<Path>>(&self, path: P) -> Result<Json> {
-        let global_config_file_path = self.cfg.home_path.join(".config/rotfiles/dotconfig.json");
+    /// Whether the destination differs from what was last rendered into it.
+    /// Entries from before content hashes were recorded fall back to mtimes.
+    fn destination_modified(&self, entry: &Entry) -> Result<bool> {
+        let file_mtime = file_mtime(&entry.destination)?;
+        debug!("Database modification time: {:?}", entry.last_updated);
+        debug!("Dotfile modification time: {:?}", file_mtime);
+
+        if entry.mtime == Some(file_mtime) {
+            return Ok(false);
+        }
+        match entry.hash {
+            Some(ref hash) => {
+                let contents = read_file(&entry.destination)?;
+                Ok(database::content_hash(&contents) != *hash)
+            }
+            None => Ok(file_mtime > entry.last_updated),
+        }
+    }
+
+    /// Whether rendering the template now gives a different result than last time
+    fn template_changed(&self, renderer: &Renderer, entry: &Entry) -> Result<bool> {
+        match entry.hash {
+            Some(ref hash) => {
+                let rendered = self.render_template_with(renderer, &entry.template_path)?;
+                Ok(database::content_hash(&rendered) != *hash)
+            }
+            None => {
+                let data_changed = match data::find_data_file(&entry.template_path) {
+                    Some(p) => file_mtime(p)? > entry.last_updated,
+                    None => false,
+                };
+                Ok(file_mtime(&entry.template_path)? > entry.last_updated || data_changed)
+            }
+        }
+    }
+
+    pub fn file_status(&self, entry: &Entry) -> Result<FileStatus> {
+        self.file_status_with(&self.renderer()?, entry)
+    }
+
+    fn file_status_with(&self, renderer: &Renderer, entry: &Entry) -> Result<FileStatus> {
+        if !entry.template_path.exists() {
+            return Ok(FileStatus::TemplateMissing);
+        }
+        if !entry.destination.exists() {
+            return Ok(FileStatus::DestinationMissing);
+        }
+
+        let file_changed = self.destination_modified(entry)?;
+        let template_changed = self.template_changed(renderer, entry)?;
+
+        Ok(match (file_changed, template_changed) {
+            (false, false) => FileStatus::Clean,
+            (true, false) => FileStatus::Modified,
+            (false, true) => FileStatus::TemplateChanged,
+            (true, true) => FileStatus::Diverged,
+        })
+    }
+
+    /// Classifies every file in the database, sorted by destination path.
+    /// Files that can't be checked are reported with `FileStatus::Error`.
+    pub fn status(&self) -> Result<Vec<(PathBuf, FileStatus)>> {
+        let renderer = self.renderer()?;
+        let mut result: Vec<_> = self
+            .db
+            .entries()
+            .map(|e| {
+                let status = self.file_status_with(&renderer, e).unwrap_or_else(|err| {
+                    let causes: Vec<_> = err.iter().map(|c| c.to_string()).collect();
+                    FileStatus::Error(causes.join(": "))
+                });
+                (e.destination.clone(), status)
+            })
+            .collect();
+        result.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(result)
+    }
+
+    /// Template data that is the same for every template
+    fn global_data(&self) -> Result<Json> {
+        let global_config_file_path = self.global_config_path();
         debug!(
             "Global config path is {}",
             global_config_file_path.display()
         );
-        let global_config_file = File::open(global_config_file_path)
-            .chain_err(|| "Couldn't open global variables config file")?;
 
         // read global config
-        debug!("Reading global config");
-        let mut result =
-            serde_json::from_reader(global_config_file).chain_err(|| "Couldn't parse json file")?;
-
-        let json_path: PathBuf = {
-            let mut ostring = path.as_ref().to_path_buf().into_os_string();
-            ostring.push(".json");
-            ostring.into()
+        // it's only missing when running without ensure_workpath_exists (read-only or dry-run)
+        let global = match data::find_data_file(&global_config_file_path.with_extension("")) {
+            Some(p) => {
+                debug!("Reading global config {:?}", p);
+                data::read_data_file(&p).chain_err(|| "Couldn't read global variables config file")?
+            }
+            None => {
+                debug!("Global config not found, using defaults");
+                default_global_config()
+            }
         };
+        // later layers take precedence: facts, env and commands, global, profile,
+        // then directories and per-file in `template_data`
+        let mut result = self.runtime_data()?;
+        merge_layer(&mut result, global, "global config")?;
+
+        if let Some(profile) = self.db.profile() {
+            let data = match self.cfg.profiles.get(profile) {
+                Some(data) => data.clone(),
+                None => bail!("Profile {} is no longer defined in the config", profile),
+            };
+            result["profile"] = json!(profile);
+            merge_layer(&mut result, data, &format!("profile {}", profile))?;
+        }
+        Ok(result)
+    }
 
-        if json_path.exists() {
-            debug!("Opening json file on path {:?}", json_path);
-            let local_config_file =
-                File::open(json_path).chain_err(|| "Couldn't open local json file")?;
-            let local_data = serde_json::from_reader(local_config_file)
-                .chain_err(|| "Couldn't parse local json file")?;
-            match (&mut result, &local_data) {
-                (Json::Object(ref mut map1), Json::Object(ref map2)) => {
-                    map1.extend(map2.iter().map(|(k, v)| (k.clone(), v.clone())));
-                }
-                _ => bail!("Config files are not json objects"),
+    /// Renderer for templates in `dot_path`, with the global data loaded.
+    /// Build it once and pass it along when working on many templates.
+    pub fn renderer(&self) -> Result<Renderer> {
+        let global = self
+            .global_data()
+            .chain_err(|| "Error reading template config")?;
+        Renderer::new(&self.cfg.dot_path.join(PARTIALS_DIR), global, self.strict)
+    }
+
+    /// Global data of `renderer` merged with the directory and per-file data of a template
+    fn template_data<P: AsRef<Path>>(&self, renderer: &Renderer, path: P) -> Result<Json> {
+        let mut result = renderer.global_data().clone();
+        for data_path in self.directory_data_files(&path) {
+            debug!("Reading directory data {:?}", data_path);
+            let dir_data = data::read_data_file(&data_path)?;
+            merge_layer(&mut result, dir_data, &data_path.to_string_lossy())?;
+        }
+
+        match data::find_data_file(path.as_ref()) {
+            Some(local_path) => {
+                debug!("Opening data file on path {:?}", local_path);
+                let local_data = data::read_data_file(&local_path)?;
+                merge_layer(&mut result, local_data, &local_path.to_string_lossy())?;
             }
-        } else {
-            debug!("Local json file not found");
+            None => debug!("Local data file not found"),
         }
         Ok(result)
     }
 
+    /// Facts about this machine, environment variables listed in the config
+    /// under `env` and outputs of `commands`. Computed once per run,
+    /// command outputs may also be cached between runs.
+    fn runtime_data(&self) -> Result<Json> {
+        if let Some(data) = self.runtime_data.get() {
+            return Ok(data.clone());
+        }
+
+        let env: serde_json::Map<String, Json> = self
+            .cfg
+            .env
+            .iter()
+            .filter_map(|name| std::env::var(name).ok().map(|v| (name.clone(), json!(v))))
+            .collect();
+
+        let mut outputs = serde_json::Map::new();
+        if !self.cfg.commands.is_empty() {
+            let mut cache = commands::CommandCache::open(&self.cfg)?;
+            let mut ran = false;
+            for (name, command) in &self.cfg.commands {
+                let (output, fresh) = cache.output(name, command)?;
+                ran |= fresh;
+                outputs.insert(name.clone(), json!(output));
+            }
+            if ran && !self.db.is_read_only() {
+                cache.commit()?;
+            }
+        }
+
+        let data = json!({
+            "env": env,
+            "commands": outputs,
+            "facts": facts::gather(&self.cfg.home_path),
+        });
+        let _ = self.runtime_data.set(data.clone());
+        Ok(data)
+    }
+
+    /// `_data` files shared by all templates in a directory of `dot_path`
+    /// and its subdirectories, that apply to the template at `path`.
+    /// Outermost directory first.
+    fn directory_data_files<P: AsRef<Path>>(&self, path: P) -> Vec<PathBuf> {
+        let parent = path.as_ref().parent().unwrap_or_else(|| Path::new(""));
+        let relative = match parent.strip_prefix(&self.cfg.dot_path) {
+            Ok(relative) => relative,
+            Err(_) => return Vec::new(),
+        };
+        let mut dir = self.cfg.dot_path.clone();
+        let mut res = Vec::new();
+        for component in std::iter::once(None).chain(relative.components().map(Some)) {
+            if let Some(c) = component {
+                dir.push(c);
+            }
+            res.extend(data::find_data_file(&dir.join("_data")));
+        }
+        res
+    }
+
+    /// Renders a template with its merged data, without touching the destination
+    pub fn render_template<P: AsRef<Path>>(&self, template_path: P) -> Result<String> {
+        self.render_template_with(&self.renderer()?, template_path)
+    }
+
+    /// Renders a template for inspection. With `host`, renders as if on that
+    /// machine: its name replaces this one in `facts.hostname` and in the
+    /// `<hostname>: true` flag of the global config. `overrides` are merged
+    /// over all other data, in order.
+    pub fn preview<P: AsRef<Path>>(
+        &self,
+        template_path: P,
+        host: Option<&str>,
+        overrides: &[Json],
+    ) -> Result<String> {
+        let mut global = self
+            .global_data()
+            .chain_err(|| "Error reading template config")?;
+        if let Some(host) = host {
+            if let Some(map) = global.as_object_mut() {
+                let current = facts::hostname();
+                if map.get(&current) == Some(&Json::Bool(true)) {
+                    map.remove(&current);
+                    map.insert(host.to_owned(), json!(true));
+                }
+            }
+            global["facts"]["hostname"] = json!(host);
+        }
+        let partials_path = self.cfg.dot_path.join(PARTIALS_DIR);
+        let renderer = Renderer::new(&partials_path, global, self.strict)?;
+
+        let mut data = self
+            .template_data(&renderer, &template_path)
+            .chain_err(|| "Error reading template config")?;
+        for layer in overrides {
+            merge_layer(&mut data, layer.clone(), "overrides")?;
+        }
+        let source = read_file(&template_path)?;
+        renderer.render_template(&source, &data, template_path.as_ref())
+    }
+
+    fn render_template_with<P: AsRef<Path>>(
+        &self,
+        renderer: &Renderer,
+        template_path: P,
+    ) -> Result<String> {
+        let data = self
+            .template_data(renderer, &template_path)
+            .chain_err(|| "Error reading template config")?;
+        self.render_with_data(renderer, &data, template_path)
+    }
+
+    /// Renders a template with `data` already read by `template_data`
+    fn render_with_data<P: AsRef<Path>>(
+        &self,
+        renderer: &Renderer,
+        data: &Json,
+        template_path: P,
+    ) -> Result<String> {
+        let source = read_file(&template_path)?;
+        renderer.render_template(&source, data, template_path.as_ref())
+    }
+
+    /// Renders `source` as if it were the contents of the template at `template_path`
+    fn render_source<P: AsRef<Path>>(
+        &self,
+        renderer: &Renderer,
+        source: &str,
+        template_path: P,
+    ) -> Result<String> {
+        let data = self
+            .template_data(renderer, &template_path)
+            .chain_err(|| "Error reading template config")?;
+        renderer.render_template(source, &data, template_path.as_ref())
+    }
+
     pub fn process_file<P, U>(&mut self, template_path: P, result_path: U) -> Result<()>
     where
         P: AsRef<Path>,
         U: AsRef<Path>,
     {
-        let mut handlebars = Handlebars::new();
+        let renderer = self.renderer()?;
+        let data = self
+            .template_data(&renderer, &template_path)
+            .chain_err(|| "Error reading template config")?;
+        self.process_file_with(&renderer, &data, template_path, result_path)
+    }
+
+    fn process_file_with<P, U>(
+        &mut self,
+        renderer: &Renderer,
+        data: &Json,
+        template_path: P,
+        result_path: U,
+    ) -> Result<()>
+    where
+        P: AsRef<Path>,
+        U: AsRef<Path>,
+    {
+        let result_key = result_path.as_ref().to_owned();
+        // last rendered output, when the destination has been changed since
+        let mut merge_base = None;
 
         debug!("Database check on file {}", result_path.as_ref().display());
         let add_entry = !result_path.as_ref().exists() || match self.ensure_template_newer_than_file(&result_path) {
@@ -216,83 +710,418 @@ impl App {
                 debug!("Not in database");
                 true
             },
-            Err(_) => bail!("Error comparing modification times"),
+            Err(e @ Error(ErrorKind::FileNewerThanTemplate(_), _)) => {
+                match self.db.get(&result_key).and_then(|e| e.last_rendered.clone()) {
+                    Some(base) => {
+                        debug!("File changed since last render, merging");
+                        merge_base = Some(base);
+                        false
+                    }
+                    None => return Err(e),
+                }
+            },
+            Err(e) => return Err(e).chain_err(|| "Error comparing modification times"),
         };
         
+        // render first, a template that fails to render must not leave an entry behind
+        let rendered = self.render_with_data(renderer, data, &template_path)?;
+
         if add_entry {
             debug!("File {} not in database. Adding", result_path.as_ref().display());
-            self.db.add_entry(Entry {
-                template_path: template_path.as_ref().to_path_buf(),
-                config_path: None,
-                destination: result_path.as_ref().to_path_buf(),
-                last_updated: SystemTime::now(),
-            });
+            self.db.add_entry(Entry::new(
+                template_path.as_ref().to_path_buf(),
+                result_path.as_ref().to_path_buf(),
+            ));
         }
 
-        let data = self
-            .get_template_config_data(&template_path)
-            .chain_err(|| "Error reading template config")?;
-
-        handlebars
-            .register_template_file("file", &template_path)
-            .chain_err(|| {
-                format!(
-                    "Could not parse template file: {}",
-                    template_path.as_ref().display()
-                )
-            })?;
+        if let Some(base) = merge_base {
+            if base == rendered {
+                // only the deployed file changed, there is nothing to merge in
+                bail!(ErrorKind::FileNewerThanTemplate(
+                    result_path.as_ref().to_string_lossy().into()
+                ));
+            }
+            return self.merge_into_file(&base, rendered, &result_key);
+        }
 
         if result_path.as_ref().exists() {
-            self.backup_file(&result_path)
+            self.backup_file(&result_path, Reason::Update)
                 .chain_err(|| "Error backing file up")?;
         }
 
-        ensure_parent_exists(&result_path).chain_err(|| "Could not create parent directories")?;
-        let mut file = File::create(&result_path).chain_err(|| "Could not create result file")?;
         debug!("Writing template");
-        write!(
-            file,
-            "{}",
-            handlebars
-                .render("file", &data)
-                .chain_err(|| "Could not render template")?
-        )
-        .chain_err(|| "Error writing result file")?;
+        self.write_file(&result_path, &rendered)
+            .chain_err(|| "Error writing result file")?;
 
+        let mtime = self.written_mtime(&result_path);
         self.db
-            .touch(&result_path.as_ref().to_owned())
+            .record_render(&result_key, rendered, mtime)
             .chain_err(|| "Error updating file modtime")?;
 
         Ok(())
     }
 
-    fn backup_file<P>(&self, path: P) -> Result<PathBuf>
+    /// Three-way merges a fresh render into a destination that was edited
+    /// since `base` was written to it
+    fn merge_into_file(&mut self, base: &str, rendered: String, result_path: &PathBuf) -> Result<()> {
+        let deployed = read_file(result_path)?;
+        let merged = merge::merge3(base, &rendered, &deployed);
+
+        self.backup_file(result_path, Reason::Merge)
+            .chain_err(|| "Error backing file up")?;
+
+        self.write_file(result_path, &merged.text)
+            .chain_err(|| "Error writing merged file")?;
+        // the destination now differs from the render, so no mtime hint is kept
+        // and it shows up as modified until its changes are pulled
+        self.db
+            .record_render(result_path, rendered, None)
+            .chain_err(|| "Error updating file modtime")?;
+
+        if merged.conflicts > 0 {
+            eprintln!(
+                "{} conflicts merging template into {}",
+                merged.conflicts,
+                result_path.display()
+            );
+            if !self.is_dry_run() && std::io::stdin().is_terminal() {
+                run_editor(result_path).chain_err(|| "Could not resolve conflicts")?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Folds edits made to a deployed file back into its template, keeping
+    /// template expressions whose output was not edited
+    pub fn pull_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let (template_path, result_path) = self
+            .resolve_targets(&[path.as_ref().to_owned()])?
+            .remove(0);
+
+        let renderer = self.renderer()?;
+        let deployed = read_file(&result_path)?;
+        let rendered = self.render_template_with(&renderer, &template_path)?;
+        if deployed != rendered {
+            let source = read_file(&template_path)?;
+            let merged = merge::reverse_merge(&source, &rendered, &deployed);
+            if self.render_source(&renderer, &merged, &template_path)? != deployed {
+                warn!(
+                    "Template {} does not reproduce {} exactly after pull",
+                    template_path.display(),
+                    result_path.display()
+                );
+            }
+            self.write_file(&template_path, &merged)
+                .chain_err(|| "Could not write merged template")?;
+        } else {
+            println!("{} is up to date with its template", result_path.display());
+        }
+
+        if !self.db.in_database(&result_path) {
+            self.db
+                .add_entry(Entry::new(template_path, result_path.clone()));
+        }
+        let mtime = self.written_mtime(&result_path);
+        self.db.record_render(&result_path, deployed, mtime)?;
+        Ok(())
+    }
+
+    /// Backups of the file at `path` (a destination or its template),
+    /// or of every file if no path is given. Oldest first.
+    pub fn list_backups(&self, path: Option<&Path>) -> Result<Vec<&BackupRecord>> {
+        match path {
+            Some(p) => {
+                let destination = self.destination_of(p)?;
+                Ok(self
+                    .backups
+                    .all()
+                    .filter(|r| r.destination == destination)
+                    .collect())
+            }
+            None => Ok(self.backups.all().collect()),
+        }
+    }
+
+    /// Puts a backup of `path` back in place: the newest one taken no later
+    /// than `at`, or simply the newest one. The current file is backed up first.
+    pub fn restore_file<P: AsRef<Path>>(&mut self, path: P, at: Option<SystemTime>) -> Result<PathBuf> {
+        let destination = self.destination_of(&path)?;
+        let backup = match self.backups.find(&destination, at) {
+            Some(r) => r.backup.clone(),
+            None => bail!("No backup of {} found", destination.display()),
+        };
+        if !backup.exists() && !self.is_dry_run() {
+            bail!("Backup file {} is missing", backup.display());
+        }
+
+        if destination.exists() {
+            self.backup_file(&destination, Reason::Restore)
+                .chain_err(|| "Error backing file up")?;
+        }
+        self.copy_file(&backup, &destination)
+            .chain_err(|| format!("Could not restore {}", destination.display()))?;
+        Ok(backup)
+    }
+
+    /// Transactions that can be undone, oldest first
+    pub fn transactions(&self) -> impl Iterator<Item = &Transaction> {
+        self.backups.transactions()
+    }
+
+    /// Reverts every destination changed by a transaction (the last one if
+    /// `id` is None) and rolls their database entries back.
+    /// Current files are backed up before being replaced.
+    pub fn undo(&mut self, id: Option<u64>) -> Result<Transaction> {
+        let transaction = match (self.backups.transaction(id), id) {
+            (Some(t), _) => t.clone(),
+            (None, Some(id)) => bail!("No transaction {}", id),
+            (None, None) => bail!("Nothing to undo"),
+        };
+        if !self.is_dry_run() {
+            for backup in transaction.changes.iter().filter_map(|c| c.backup.as_ref()) {
+                if !backup.exists() {
+                    bail!("Backup file {} is missing", backup.display());
+                }
+            }
+        }
+
+        for change in transaction.changes.iter().rev() {
+            let destination = &change.destination;
+            if destination.exists() && !change.adopted {
+                self.backup_file(destination, Reason::Restore)
+                    .chain_err(|| "Error backing file up")?;
+            }
+            match change.backup {
+                Some(ref backup) => self
+                    .copy_file(backup, destination)
+                    .chain_err(|| format!("Could not restore {}", destination.display()))?,
+                None if destination.exists() && !change.adopted => {
+                    self.remove_path(destination)?
+                }
+                None => {}
+            }
+            match change.entry {
+                Some(ref entry) => {
+                    self.db.add_entry(entry.clone());
+                }
+                None => self.db.rm_key(destination),
+            }
+        }
+        self.backups.remove_transaction(transaction.id);
+        Ok(transaction)
+    }
+
+    /// Deletes backups that the configured retention policy no longer keeps.
+    /// Backups whose files have disappeared are dropped from the index as well.
+    /// Backups needed to undo the last transaction are always kept.
+    /// Returns the records of removed backups.
+    pub fn gc(&mut self) -> Result<Vec<BackupRecord>> {
+        let protected: Vec<PathBuf> = self
+            .backups
+            .transaction(None)
+            .map(|t| t.changes.iter().filter_map(|c| c.backup.clone()).collect())
+            .unwrap_or_default();
+        let mut candidates = Vec::new();
+        let mut missing = Vec::new();
+        for record in self.backups.all().filter(|r| !protected.contains(&r.backup)) {
+            match std::fs::metadata(&record.backup) {
+                Ok(meta) => candidates.push((record.clone(), meta.len())),
+                Err(_) => missing.push(record.backup.clone()),
+            }
+        }
+        for backup in missing {
+            self.backups.remove(&backup);
+        }
+
+        let expired =
+            backup::expired_backups(&self.cfg.retention, &candidates, SystemTime::now())?;
+        let mut removed = Vec::new();
+        for backup in expired {
+            self.remove_path(&backup)?;
+            removed.extend(self.backups.remove(&backup));
+        }
+        Ok(removed)
+    }
+
+    /// Absolute destination of a path that is either a destination or a template
+    fn destination_of<P: AsRef<Path>>(&self, path: P) -> Result<PathBuf> {
+        let abs = PathAbs::new(path.as_ref())
+            .chain_err(|| {
+                format!(
+                    "Could not convert {} to absolute path",
+                    path.as_ref().display()
+                )
+            })?
+            .as_path()
+            .to_owned();
+        if abs.starts_with(&self.cfg.dot_path) {
+            self.filename_to_dotfile(&abs)
+        } else {
+            Ok(abs)
+        }
+    }
+
+    /// Unified diff from the current destination to what `process_file` would write.
+    /// Returns `None` if the destination is already up to date.
+    pub fn diff_file<P, U>(
+        &self,
+        renderer: &Renderer,
+        template_path: P,
+        result_path: U,
+    ) -> Result<Option<String>>
+    where
+        P: AsRef<Path>,
+        U: AsRef<Path>,
+    {
+        let rendered = self.render_template_with(renderer, &template_path)?;
+        let (current, old_header) = if result_path.as_ref().exists() {
+            (
+                read_file(&result_path)?,
+                result_path.as_ref().display().to_string(),
+            )
+        } else {
+            (String::new(), "/dev/null".to_owned())
+        };
+
+        if current == rendered {
+            return Ok(None);
+        }
+
+        let diff = similar::TextDiff::from_lines(&current, &rendered);
+        let new_header = template_path.as_ref().display().to_string();
+        Ok(Some(
+            diff.unified_diff()
+                .header(&old_header, &new_header)
+                .to_string(),
+        ))
+    }
+
+    /// Resolves paths given on the command line (either templates or their
+    /// destinations) to (template, destination) pairs.
+    /// An empty list means every file in the dotfiles directory.
+    pub fn resolve_targets(&self, paths: &[PathBuf]) -> Result<Vec<(PathBuf, PathBuf)>> {
+        if paths.is_empty() {
+            return self.resolve_targets_with(&self.renderer()?, paths);
+        }
+        self.resolve_paths(paths)
+    }
+
+    /// Same as `resolve_targets`, evaluating inclusion rules with `renderer`
+    pub fn resolve_targets_with(
+        &self,
+        renderer: &Renderer,
+        paths: &[PathBuf],
+    ) -> Result<Vec<(PathBuf, PathBuf)>> {
+        if paths.is_empty() {
+            return self
+                .files_to_process_with(renderer)?
+                .into_iter()
+                .map(|t| self.filename_to_dotfile(&t).map(|d| (t, d)))
+                .collect();
+        }
+        self.resolve_paths(paths)
+    }
+
+    fn resolve_paths(&self, paths: &[PathBuf]) -> Result<Vec<(PathBuf, PathBuf)>> {
+        paths
+            .iter()
+            .map(|p| {
+                let abs = PathAbs::new(p)
+                    .chain_err(|| format!("Could not convert {} to absolute path", p.display()))?
+                    .as_path()
+                    .to_owned();
+                match self.filename_to_dotfile(&abs) {
+                    Ok(dest) => Ok((abs, dest)),
+                    Err(_) => Ok((self.dotfile_to_filename(&abs)?, abs)),
+                }
+            })
+            .collect()
+    }
+
+    /// Where backups of `destination` are kept: a tree mirroring the
+    /// destination's location under `home/` for files in `home_path`,
+    /// or under `root/` for everything else
+    fn backup_dir_of(&self, destination: &Path) -> PathBuf {
+        let parent = destination.parent().unwrap_or_else(|| Path::new("/"));
+        match parent.strip_prefix(&self.cfg.home_path) {
+            Ok(rel) => self.cfg.backup_path.join("home").join(rel),
+            Err(_) => self
+                .cfg
+                .backup_path
+                .join("root")
+                .join(parent.strip_prefix("/").unwrap_or(parent)),
+        }
+    }
+
+    fn backup_file<P>(&mut self, path: P, reason: Reason) -> Result<PathBuf>
     where
         P: AsRef<Path>,
     {
         let p = path.as_ref();
-        let (mut file_name, ext) = match (p.file_stem(), p.extension()) {
-            (Some(x), Some(y)) => (x.to_owned(), y.to_owned()),
-            (None, Some(y)) => (OsString::new(), y.to_owned()),
-            (Some(x), None) => (x.to_owned(), OsString::new()),
-            (None, None) => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "No filename on file to backup",
-                )
-                .into())
-            }
+        let file_name = match p.file_name() {
+            Some(name) => name.to_owned(),
+            None => return Err(std::io::Error::other("No filename on file to backup").into()),
         };
+        let dir = self.backup_dir_of(p);
+        let timestamp = SystemTime::now();
+        let mut base_name = file_name;
+        base_name.push(
+            DateTime::<Utc>::from(timestamp)
+                .format(".%Y-%m-%dT%H:%M:%S%.3f")
+                .to_string(),
+        );
 
-        let mut result_path = self.cfg.backup_path.clone();
+        // Several backups within the same millisecond get a counter appended.
+        // The name is claimed with create_new so no backup is ever overwritten.
+        if !self.is_dry_run() {
+            std::fs::create_dir_all(&dir)
+                .chain_err(|| format!("Could not create directory {}", dir.display()))?;
+        }
+        let mut result_path;
+        let mut n = 0;
+        loop {
+            let mut name = base_name.clone();
+            if n > 0 {
+                name.push(format!("-{}", n));
+            }
+            result_path = dir.join(name);
+            n += 1;
+            if self.is_dry_run() {
+                if !result_path.exists() {
+                    break;
+                }
+                continue;
+            }
+            match std::fs::OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&result_path)
+            {
+                Ok(_) => break,
+                Err(ref e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+                Err(e) => {
+                    return Err(e).chain_err(|| {
+                        format!("Could not create backup file {}", result_path.display())
+                    })
+                }
+            }
+        }
 
-        file_name.push(format!(
-            "{}",
-            Utc::now().format("%Y-%m-%dT%H:%M:%S").to_string()
-        ));
+        let record = BackupRecord {
+            destination: p.to_owned(),
+            backup: result_path.clone(),
+            timestamp,
+            reason,
+        };
 
-        result_path.push(file_name);
-        result_path.set_extension(ext);
+        if let Some(ref mut plan) = self.plan {
+            plan.push(Action::Backup {
+                from: p.to_owned(),
+                to: result_path.clone(),
+            });
+            self.backups.add(record);
+            return Ok(result_path);
+        }
 
         debug!("Attempting backup of {:?} to {:?}", p, &result_path);
         std::fs::copy(&path, &result_path).chain_err(|| {
@@ -303,36 +1132,66 @@ impl App {
             )
         })?;
         debug!("Backup of {:?} to {:?} complete", p, &result_path);
+        self.backups.add(record);
 
         Ok(result_path)
     }
 
-    pub fn files_to_process(&self) -> impl Iterator<Item = PathBuf> {
-        let glob_path = self.cfg.dot_path.to_string_lossy() + "/**/*";
-        let match_options = glob::MatchOptions {
-            case_sensitive: true,
-            require_literal_separator: false,
-            require_literal_leading_dot: true,
-        };
+    /// Templates whose inclusion rule holds on this machine. Templates whose
+    /// rule can't be evaluated are reported and left out.
+    pub fn files_to_process(&self) -> Result<Vec<PathBuf>> {
+        self.files_to_process_with(&self.renderer()?)
+    }
+
+    fn files_to_process_with(&self, renderer: &Renderer) -> Result<Vec<PathBuf>> {
+        let mut res = Vec::new();
+        for template in self.templates() {
+            match self.is_included_with(renderer, &template) {
+                Ok(true) => res.push(template),
+                Ok(false) => {}
+                Err(e) => {
+                    eprintln!("Could not check whether to deploy {}\n{}", template.display(), e);
+                    print_causes(&e);
+                }
+            }
+        }
+        Ok(res)
+    }
+
+    /// Evaluates the `_when` rule in the data of a template. A boolean is used
+    /// as is, a string is rendered as a template with the same data and
+    /// excludes the file if it renders to "", "false" or "0".
+    /// Templates without a rule are always included.
+    pub fn is_included<P: AsRef<Path>>(&self, template_path: P) -> Result<bool> {
+        self.is_included_with(&self.renderer()?, template_path)
+    }
 
+    fn is_included_with<P: AsRef<Path>>(
+        &self,
+        renderer: &Renderer,
+        template_path: P,
+    ) -> Result<bool> {
+        let data = self
+            .template_data(renderer, &template_path)
+            .chain_err(|| "Error reading template config")?;
+        rule_holds(renderer, &data)
+    }
+
+    /// Every template in the dotfiles directory, whether included or not
+    fn templates(&self) -> impl Iterator<Item = PathBuf> {
+        let glob_path = self.cfg.dot_path.to_string_lossy() + "/**/*";
         let dot_path = self.cfg.dot_path.clone();
 
-        glob::glob_with(&glob_path, match_options)
+        glob::glob_with(&glob_path, GLOB_OPTIONS)
             .expect("Incorrect path")
             .filter_map(std::result::Result::ok) // filter out non-readable files
-            // filter out json files
-            .filter(|p| {
-                p.is_file() && !(p.extension().is_some() && p.extension().unwrap() == "json")
-            })
+            .filter(|p| p.is_file() && !is_data_file(p))
             .filter(move |p| {
                 let relative = p.strip_prefix(dot_path.clone()).unwrap();
                 // debug!("Relative path: {}", relative.display());
                 // debug!("First char: {:?}", relative.to_string_lossy().chars().nth(0));
-                match relative.to_string_lossy().chars().nth(0) {
-                    Some('.') => false,
-                    None => false,
-                    _ => true,
-                }
+                !matches!(relative.to_string_lossy().chars().next(), Some('.') | None)
+                    && !relative.starts_with(PARTIALS_DIR)
             })
         // .filter(|p| {
         //     p
@@ -403,13 +1262,136 @@ impl App {
     }
 
     pub fn process_all_files(&mut self) -> Result<()> {
-        for fname in self.files_to_process() {
+        let renderer = self.renderer()?;
+        self.process_files_except(&renderer, &[], Vec::new())
+    }
+
+    /// Like `process_all_files`, but first asks `choose` what to do with every
+    /// destination that already exists and is not in the database yet.
+    /// `choose` is given the destination and the diff to its rendered template.
+    /// Destinations that are identical to their render are adopted without asking.
+    /// Destinations whose template can't be rendered are reported and left alone.
+    pub fn bootstrap<F>(&mut self, mut choose: F) -> Result<()>
+    where
+        F: FnMut(&Path, &str) -> Result<Bootstrap>,
+    {
+        let renderer = self.renderer()?;
+        let mut handled = Vec::new();
+        let mut adopted = Vec::new();
+        for (template, destination) in self.resolve_targets_with(&renderer, &[])? {
+            if !destination.exists() || self.db.in_database(&destination) {
+                continue;
+            }
+            let diff = match self.diff_file(&renderer, &template, &destination) {
+                Ok(diff) => diff,
+                Err(e) => {
+                    eprintln!("Could not bootstrap {}\n{}", destination.display(), e);
+                    print_causes(&e);
+                    handled.push(destination);
+                    continue;
+                }
+            };
+            let choice = match diff {
+                None => Bootstrap::Adopt,
+                Some(diff) => choose(&destination, &diff)?,
+            };
+            debug!("Bootstrapping {:?}: {:?}", destination, choice);
+            match choice {
+                Bootstrap::Adopt => {
+                    if let Err(e) = self.adopt_file(&renderer, &template, &destination) {
+                        eprintln!("Could not adopt {}\n{}", destination.display(), e);
+                        print_causes(&e);
+                        handled.push(destination);
+                        continue;
+                    }
+                    adopted.push(Change {
+                        destination: destination.clone(),
+                        backup: None,
+                        entry: None,
+                        adopted: true,
+                    });
+                }
+                Bootstrap::Overwrite => continue,
+                Bootstrap::Skip => {}
+            }
+            handled.push(destination);
+        }
+        self.process_files_except(&renderer, &handled, adopted)
+    }
+
+    /// Starts managing an existing destination without changing it. The render
+    /// of its template becomes the merge base, so differences in the
+    /// destination are treated as local edits.
+    fn adopt_file(
+        &mut self,
+        renderer: &Renderer,
+        template_path: &Path,
+        result_path: &Path,
+    ) -> Result<()> {
+        let rendered = self.render_template_with(renderer, template_path)?;
+        let result_key = result_path.to_owned();
+        let mtime = if read_file(result_path)? == rendered {
+            self.written_mtime(result_path)
+        } else {
+            None
+        };
+        self.db
+            .add_entry(Entry::new(template_path.to_owned(), result_key.clone()));
+        self.db.record_render(&result_key, rendered, mtime)
+    }
+
+    /// Processes every included template except those deploying to `skip`,
+    /// recording `changes` made beforehand in the same transaction
+    fn process_files_except(
+        &mut self,
+        renderer: &Renderer,
+        skip: &[PathBuf],
+        changes: Vec<Change>,
+    ) -> Result<()> {
+        let mut transaction = Transaction::new(self.backups.next_transaction_id());
+        transaction.changes = changes;
+        let mut excluded = 0;
+        for fname in self.templates().collect::<Vec<_>>() {
             debug!("File processing loop entry on {:?}", fname);
-            println!("Processing {}", fname.display());
             let result_fname = self.filename_to_dotfile(&fname)?;
-            let res = self.process_file(&fname, &result_fname);
+            if skip.contains(&result_fname) {
+                continue;
+            }
+            let included = self
+                .template_data(renderer, &fname)
+                .chain_err(|| "Error reading template config")
+                .and_then(|data| Ok((rule_holds(renderer, &data)?, data)));
+            let data = match included {
+                Ok((true, data)) => data,
+                Ok((false, _)) => {
+                    println!("Excluded {}", fname.display());
+                    excluded += 1;
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("Could not check whether to deploy {}\n{}", fname.display(), e);
+                    print_causes(&e);
+                    continue;
+                }
+            };
+            println!("Processing {}", fname.display());
+            let entry = self.db.get(&result_fname).cloned();
+            let res = self.process_file_with(renderer, &data, &fname, &result_fname);
             match res {
-                Ok(_) => {}
+                Ok(_) => {
+                    let backup = self
+                        .backups
+                        .for_destination(&result_fname)
+                        .filter(|r| r.timestamp >= transaction.timestamp)
+                        .map(|r| r.backup.clone())
+                        .next();
+                    transaction.changes.push(Change {
+                        destination: result_fname,
+                        backup,
+                        entry,
+                        adopted: false,
+                    });
+                }
                 Err(e) => {
                     eprintln!(
                         "Could not process file: {}->{}\n{}",
@@ -417,15 +1399,30 @@ impl App {
                         result_fname.display(),
                         e
                     );
-                    for e in e.iter().skip(1) {
-                        eprintln!("caused by: {}", e);
-                    }
-                    if let Ok(_) = std::env::var("RUST_BACKTRACE") {
-                        eprintln!("Backtrace:\n{:?}", e.backtrace())
-                    }
+                    print_causes(&e);
                 }
             }
         }
+
+        if excluded > 0 {
+            println!("{} files are excluded on this machine", excluded);
+        }
+        // a dry run records nothing and only plans the removals,
+        // which are listed with the other planned actions
+        if !transaction.changes.is_empty() {
+            if !self.is_dry_run() {
+                println!(
+                    "Recorded as transaction {}, use `rotfiles undo` to revert it",
+                    transaction.id
+                );
+            }
+            self.backups.add_transaction(transaction);
+        }
+
+        let removed = self.gc().chain_err(|| "Could not prune old backups")?;
+        if !removed.is_empty() && !self.is_dry_run() {
+            println!("Removed {} old backups", removed.len());
+        }
         Ok(())
     }
 
@@ -437,50 +1434,33 @@ impl App {
                 path.as_ref().display()
             )
         })?;
-        ensure_parent_exists(&result_path)
-            .chain_err(|| format!("Cant create parents of {}", path.as_ref().display()))?;
         debug!(
             "Attempting copy from {:?} to {:?}",
             path.as_ref(),
             &result_path
         );
-        std::fs::copy(&path, &result_path).chain_err(|| {
+        let deployed = read_file(&path)?;
+        let contents = sanitize(&deployed);
+        self.write_file(&result_path, &contents).chain_err(|| {
             format!(
                 "Could not copy {} to {}",
                 path.as_ref().display(),
                 result_path.display()
             )
         })?;
-        sanitize_file(&result_path)
-            .chain_err(|| format!("Error sanitizing {}", result_path.display()))?;
-
-        let modtime = path
-            .as_ref()
-            .metadata()
-            .chain_err(|| "Can't access file's metadata")?
-            .modified()
-            .chain_err(|| "Can't access file's modification time")?;
-
-        let mut entry = Entry {
-            template_path: result_path.clone(),
-            config_path: None,
-            destination: path.as_ref().to_owned(),
-            last_updated: modtime,
-        };
+
+        let mut entry = Entry::new(result_path.clone(), path.as_ref().to_owned());
+        entry.hash = Some(database::content_hash(&deployed));
+        entry.last_rendered = Some(deployed);
+        entry.mtime = Some(file_mtime(&path)?);
 
         if generate_config {
-            let json_fname = {
-                let mut p = result_path.as_os_str().to_owned();
-                p.push(".json");
-                p
-            };
-            entry.config_path = Some(PathBuf::from(&json_fname));
+            let json_fname = self.json_filename(&result_path);
+            entry.config_path = Some(json_fname.clone());
 
-            let json_value = json!({get_hostname(): true});
-            let mut json_file =
-                File::create(json_fname).chain_err(|| "Could not create json file")?;
-            write!(json_file, "{}", json_value.to_string())
-                .chain_err(|| "Could not write to json file")?;
+            let json_value = json!({facts::hostname(): true});
+            self.write_file(&json_fname, &json_value.to_string())
+                .chain_err(|| "Could not create json file")?;
         }
 
         self.db.add_entry(entry);
@@ -496,17 +1476,15 @@ impl App {
         debug!("Attempting to remove {}", dot_path.display());
 
         let template_path = self.dotfile_to_filename(&dot_path).unwrap();
-        let json_fname = self.json_filename(&template_path);
 
         self.db.rm_key(&dot_path);
 
-        if json_fname.exists() {
-            debug!("Removing {}", json_fname.display());
-            std::fs::remove_file(&json_fname)
-            .chain_err(|| format!("Could not remove file {}", json_fname.display()))?;
+        for data_fname in data::data_files(&template_path) {
+            debug!("Removing {}", data_fname.display());
+            self.remove_path(&data_fname)?;
         }
         debug!("Removing {}", template_path.display());
-        std::fs::remove_file(&template_path)
+        self.remove_path(&template_path)
             .chain_err(|| "Could not remove file template")?;
 
 
@@ -514,6 +1492,9 @@ impl App {
     }
 
     pub fn edit_file<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        if self.is_dry_run() {
+            bail!("Editing is not supported in dry-run mode");
+        }
         let path = PathAbs::new(&path)
             .chain_err(|| {
                 format!(
@@ -540,16 +1521,7 @@ impl App {
         let template_path = self
             .dotfile_to_filename(&path)
             .chain_err(|| "Could not convert to template filename")?;
-        let editor = std::env::var("EDITOR").chain_err(|| "Could not get $EDITOR env variable")?;
-
-        match subprocess::Exec::cmd(editor)
-            .args(&[template_path.as_os_str()])
-            .join()
-            .chain_err(|| "Failed to edit")?
-        {
-            subprocess::ExitStatus::Exited(0) => (),
-            _ => bail!("Editor probably failed"),
-        }
+        run_editor(&template_path)?;
 
         println!("Applying template");
         self.process_file(&template_path, &path)
@@ -565,6 +1537,51 @@ impl App {
     }
 }
 
+/// Deep merges one layer of template data, which has to be a json object
+fn merge_layer(data: &mut Json, layer: Json, name: &str) -> Result<()> {
+    if !layer.is_object() {
+        bail!("Data in {} is not a json object", name);
+    }
+    data::deep_merge(data, layer).chain_err(|| format!("Could not merge data from {}", name))
+}
+
+/// Evaluates the `_when` rule in the merged data of a template
+fn rule_holds(renderer: &Renderer, data: &Json) -> Result<bool> {
+    match data.get("_when") {
+        None => Ok(true),
+        Some(Json::Bool(b)) => Ok(*b),
+        Some(Json::String(rule)) => {
+            let res = renderer
+                .render(rule, data)
+                .chain_err(|| format!("Could not evaluate rule {}", rule))?;
+            Ok(!matches!(res.trim(), "" | "false" | "0"))
+        }
+        Some(_) => bail!("_when must be a boolean or a string"),
+    }
+}
+
+/// Json files are always template data. Toml and yaml files only when they
+/// sit next to their template or are a directory's `_data` file, so configs
+/// written in those formats can still be templates.
+fn is_data_file(path: &Path) -> bool {
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("json") => true,
+        Some(ext) if data::EXTENSIONS.contains(&ext) => {
+            path.file_stem() == Some("_data".as_ref()) || path.with_extension("").is_file()
+        }
+        _ => false,
+    }
+}
+
+fn print_causes(e: &Error) {
+    for e in e.iter().skip(1) {
+        eprintln!("caused by: {}", e);
+    }
+    if std::env::var("RUST_BACKTRACE").is_ok() {
+        eprintln!("Backtrace:\n{:?}", e.backtrace())
+    }
+}
+
 fn read_file<P: AsRef<Path>>(path: P) -> Result<String> {
     let mut res = String::new();
     let mut file = File::open(&path)
@@ -574,6 +1591,27 @@ fn read_file<P: AsRef<Path>>(path: P) -> Result<String> {
     Ok(res)
 }
 
+fn file_mtime<P: AsRef<Path>>(path: P) -> Result<SystemTime> {
+    path.as_ref()
+        .metadata()
+        .chain_err(|| format!("Can't access metadata of {}", path.as_ref().display()))?
+        .modified()
+        .chain_err(|| "Can't access modification time")
+}
+
+fn run_editor<P: AsRef<Path>>(path: P) -> Result<()> {
+    let editor = std::env::var("EDITOR").chain_err(|| "Could not get $EDITOR env variable")?;
+
+    match subprocess::Exec::cmd(editor)
+        .args(&[path.as_ref().as_os_str()])
+        .join()
+        .chain_err(|| "Failed to edit")?
+    {
+        subprocess::ExitStatus::Exited(0) => Ok(()),
+        _ => bail!("Editor probably failed"),
+    }
+}
+
 fn ensure_parent_exists<P: AsRef<Path>>(path: P) -> Result<()> {
     let p = path
         .as_ref()
@@ -583,32 +1621,10 @@ fn ensure_parent_exists<P: AsRef<Path>>(path: P) -> Result<()> {
     Ok(())
 }
 
-fn sanitize_file<P: AsRef<Path>>(path: P) -> Result<()> {
-    debug!("Sanitizing file {}", path.as_ref().display());
-    let contents = read_file(&path)?
-        .replace("{{", "\\{{")
-        .replace("}}", "\\}}");
-    let mut file = File::create(&path).chain_err(|| {
-        format!(
-            "Could not overwrite file {} for sanitization",
-            path.as_ref().display()
-        )
-    })?;
-    write!(file, "{}", contents)?;
-    debug!(
-        "File {} sanitized with modtime {:?}",
-        path.as_ref().display(),
-        path.as_ref().metadata()?.modified()?
-    );
-    Ok(())
+fn default_global_config() -> Json {
+    json!({facts::hostname(): true})
 }
 
-fn get_hostname() -> String {
-    let mut result = String::new();
-    let mut f = File::open("/etc/hostname").unwrap();
-    f.read_to_string(&mut result).unwrap();
-    String::from(result.trim_end())
-}
 
 fn yes_no_prompt(prompt: &str) -> Result<bool> {
     println!("{} [Yn]", prompt);
@@ -621,7 +1637,7 @@ fn yes_no_prompt(prompt: &str) -> Result<bool> {
 
         if bytes_read == 2 {
             // [yn] + newline  Gotta hope there is no windows users
-            let res = line.chars().nth(0).unwrap().to_lowercase().nth(0).unwrap();
+            let res = line.chars().next().unwrap().to_lowercase().next().unwrap();
             if res == 'y' {
                 return Ok(true);
             } else if res == 'n' {
@@ -640,6 +1656,7 @@ fn yes_no_prompt(prompt: &str) -> Result<bool> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use handlebars::Handlebars;
     extern crate tempfile;
 
     fn create_file_with_contents<P: AsRef<Path>>(path: P, contents: &str) -> Result<()> {
@@ -671,20 +1688,173 @@ mod tests {
     }
 
     #[test]
-    fn test_backup() {
+    fn test_backup() {
+        let _ = pretty_env_logger::try_init();
+        pretty_err_catcher(|| {
+            let mut app = App::new_test()?;
+            let mut file = tempfile::NamedTempFile::new().chain_err(|| "Can't create temp file")?;
+            let orig_content = "This is a test of backup functionality. Some unicode: ąąąćććććęęę";
+            write!(file, "{}", orig_content).chain_err(|| "Can't write to temp file")?;
+            let location = app
+                .backup_file(file.path(), Reason::Update)
+                .chain_err(|| "Failed to back file up")?;
+
+            let new_content = read_file(&location).chain_err(|| "Couldnt read backed up file")?;
+
+            assert_eq!(orig_content, new_content);
+            let parent = file.path().parent().unwrap().strip_prefix("/").unwrap();
+            assert!(location.starts_with(app.cfg.backup_path.join("root").join(parent)));
+
+            // files with the same name in different directories, backed up
+            // in quick succession, never share a backup
+            let mut locations = vec![location];
+            for dir in &[".config/foo", ".config/bar", ".config/bar"] {
+                let path = app.cfg.home_path.join(dir).join("config");
+                create_file_with_contents(&path, dir)?;
+                let location = app.backup_file(&path, Reason::Update)?;
+                assert!(location.starts_with(app.cfg.backup_path.join("home").join(dir)));
+                assert_eq!(*dir, read_file(&location)?);
+                assert!(!locations.contains(&location));
+                locations.push(location);
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_restore() {
+        let _ = pretty_env_logger::try_init();
+        pretty_err_catcher(|| {
+            let mut app = App::new_test()?;
+            let result_path = app.cfg.home_path.join(".restoretest");
+            let template_path = app.dotfile_to_filename(&result_path)?;
+
+            for contents in &["one", "two", "three"] {
+                create_file_with_contents(&template_path, contents)?;
+                app.process_file(&template_path, &result_path)?;
+            }
+
+            let backups = app.list_backups(Some(&template_path))?;
+            assert_eq!(2, backups.len());
+            assert!(backups.iter().all(|r| r.reason == Reason::Update));
+            let first = backups[0].timestamp;
+
+            app.restore_file(&result_path, None)?;
+            assert_eq!("two", read_file(&result_path)?);
+            let backups = app.list_backups(Some(&result_path))?;
+            assert_eq!(3, backups.len());
+            assert_eq!(Reason::Restore, backups[2].reason);
+
+            app.restore_file(&result_path, Some(first))?;
+            assert_eq!("one", read_file(&result_path)?);
+
+            assert!(app
+                .restore_file(&result_path, Some(std::time::UNIX_EPOCH))
+                .is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_gc() {
+        let _ = pretty_env_logger::try_init();
+        pretty_err_catcher(|| {
+            let mut app = App::new_test()?;
+            let result_path = app.cfg.home_path.join(".gctest");
+            let template_path = app.dotfile_to_filename(&result_path)?;
+
+            for contents in &["one", "two", "three"] {
+                create_file_with_contents(&template_path, contents)?;
+                app.process_file(&template_path, &result_path)?;
+            }
+            let backups: Vec<_> = app
+                .list_backups(None)?
+                .iter()
+                .map(|r| r.backup.clone())
+                .collect();
+            assert_eq!(2, backups.len());
+
+            // nothing is configured, so everything is kept
+            assert!(app.gc()?.is_empty());
+
+            app.cfg.retention.keep_last = Some(1);
+            let removed = app.gc()?;
+            assert_eq!(1, removed.len());
+            assert_eq!(backups[0], removed[0].backup);
+            assert!(!backups[0].exists());
+            assert!(backups[1].exists());
+
+            // backups deleted by hand are forgotten
+            std::fs::remove_file(&backups[1])?;
+            assert!(app.gc()?.is_empty());
+            assert!(app.list_backups(None)?.is_empty());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_undo() {
+        let _ = pretty_env_logger::try_init();
+        pretty_err_catcher(|| {
+            let mut app = App::new_test()?;
+            let existing = app.cfg.home_path.join(".undoexisting");
+            let created = app.cfg.home_path.join(".undocreated");
+            let existing_template = app.dotfile_to_filename(&existing)?;
+            let created_template = app.dotfile_to_filename(&created)?;
+            create_file_with_contents(&existing, "original")?;
+
+            create_file_with_contents(&existing_template, "first")?;
+            create_file_with_contents(&created_template, "first")?;
+            app.process_all_files()?;
+            create_file_with_contents(&existing_template, "second")?;
+            create_file_with_contents(&created_template, "second")?;
+            app.process_all_files()?;
+            let ids: Vec<_> = app.transactions().map(|t| t.id).collect();
+            assert_eq!(vec![1, 2], ids);
+
+            let undone = app.undo(None)?;
+            assert_eq!(2, undone.id);
+            assert_eq!(2, undone.changes.len());
+            assert_eq!("first", read_file(&existing)?);
+            assert_eq!("first", read_file(&created)?);
+            assert_eq!(
+                Some(database::content_hash("first")),
+                app.db.get(&existing).unwrap().hash
+            );
+
+            app.undo(None)?;
+            assert_eq!("original", read_file(&existing)?);
+            assert!(!created.exists());
+            assert!(app.db.get(&existing).is_none());
+            assert!(app.db.get(&created).is_none());
+
+            assert!(app.undo(None).is_err());
+            assert!(app.undo(Some(1)).is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_init() {
         let _ = pretty_env_logger::try_init();
         pretty_err_catcher(|| {
-            let app = App::new_test()?;
-            let mut file = tempfile::NamedTempFile::new().chain_err(|| "Can't create temp file")?;
-            let orig_content = "This is a test of backup functionality. Some unicode: ąąąćććććęęę";
-            write!(file, "{}", orig_content).chain_err(|| "Can't write to temp file")?;
-            let location = app
-                .backup_file(file.path())
-                .chain_err(|| "Failed to back file up")?;
-
-            let new_content = read_file(location).chain_err(|| "Couldnt read backed up file")?;
-
-            assert_eq!(orig_content, new_content);
+            let home = tempfile::TempDir::new()?;
+            let cfg = config::Config::with_home(home.path());
+            let config_path = home.path().join(".config/rotfiles/config.json");
+
+            App::init(cfg.clone(), &config_path, true)?;
+            assert!(!config_path.exists());
+            assert!(!cfg.dot_path.exists());
+
+            let app = App::init(cfg.clone(), &config_path, false)?;
+            assert!(cfg.dot_path.is_dir());
+            assert!(cfg.backup_path.is_dir());
+            assert!(app.global_config_path().exists());
+            let loaded = config::Config::from_file(&config_path)?;
+            assert_eq!(cfg.dot_path, loaded.dot_path);
+            assert_eq!(cfg.backup_path, loaded.backup_path);
+
+            assert!(App::init(cfg, &config_path, false).is_err());
             Ok(())
         });
     }
@@ -719,27 +1889,16 @@ mod tests {
         let _ = pretty_env_logger::try_init();
         let s = "String to be left alone";
         let s2 = "String with daaangerous {{s and }}s";
-        let s2_target = r#"String with daaangerous \{{s and \}}s"#;
-
-        let mut orig_file1 = tempfile::NamedTempFile::new().unwrap();
-        write!(orig_file1, "{}", s).unwrap();
-        sanitize_file(orig_file1.path()).unwrap();
-        let mut changed_file = File::open(orig_file1.path()).unwrap();
-        let mut result1 = String::new();
-        changed_file.read_to_string(&mut result1).unwrap();
-
-        assert_eq!(result1, s);
-
-        let mut orig_file2 = tempfile::NamedTempFile::new().unwrap();
-        write!(orig_file2, "{}", s2).unwrap();
-
-        sanitize_file(orig_file2.path()).unwrap();
-        changed_file = File::open(orig_file2.path()).unwrap();
+        let s2_target = r#"String with daaangerous \{{s and }}s"#;
 
-        let mut result2 = String::new();
-        changed_file.read_to_string(&mut result2).unwrap();
+        assert_eq!(sanitize(s), s);
+        assert_eq!(sanitize(s2), s2_target);
 
-        assert_eq!(result2, s2_target);
+        // sanitized text has to render back to the original
+        let hb = Handlebars::new();
+        for case in &[s, s2, "lone }} braces", "{{{triple}}} and {{#if x}}{{/if}}"] {
+            assert_eq!(*case, hb.render_template(&sanitize(case), &json!({})).unwrap());
+        }
     }
 
     #[test]
@@ -774,6 +1933,424 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_inclusion_rules() {
+        pretty_err_catcher(|| {
+            let _ = pretty_env_logger::try_init();
+            let mut app = App::new_test()?;
+            create_file_with_contents(
+                app.global_config_path(),
+                r#"{"work": true, "gaming": false}"#,
+            )?;
+
+            let rules = [
+                ("always", None),
+                ("never", Some(r#"false"#)),
+                ("work", Some(r#""{{work}}""#)),
+                ("gaming", Some(r#""{{#if gaming}}yes{{/if}}""#)),
+                ("broken", Some(r#""{{#if}}""#)),
+            ];
+            for (name, rule) in &rules {
+                let template_path = app.cfg.dot_path.join(format!("rule{}", name));
+                create_file_with_contents(&template_path, name)?;
+                if let Some(rule) = rule {
+                    create_file_with_contents(
+                        app.json_filename(&template_path),
+                        &format!(r#"{{"_when": {}}}"#, rule),
+                    )?;
+                }
+            }
+
+            let included: Vec<_> = app.files_to_process()?;
+            assert_eq!(
+                vec![
+                    app.cfg.dot_path.join("rulealways"),
+                    app.cfg.dot_path.join("rulework")
+                ],
+                included
+            );
+            assert!(app.is_included(app.cfg.dot_path.join("rulebroken")).is_err());
+
+            app.process_all_files()?;
+            assert!(app.cfg.home_path.join(".rulework").exists());
+            assert!(!app.cfg.home_path.join(".rulegaming").exists());
+            assert!(!app.cfg.home_path.join(".rulebroken").exists());
+
+            // a broken global config is an error, not an empty list of files
+            create_file_with_contents(app.global_config_path(), "{")?;
+            assert!(app.files_to_process().is_err());
+            assert!(app.resolve_targets(&[]).is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_profiles() {
+        pretty_err_catcher(|| {
+            let _ = pretty_env_logger::try_init();
+            let mut app = App::new_test()?;
+            app.cfg
+                .profiles
+                .insert("work".to_owned(), json!({"email": "me@work.com"}));
+            create_file_with_contents(app.global_config_path(), r#"{"email": "me@home.com"}"#)?;
+            let template_path = app.cfg.dot_path.join("profiletest");
+            create_file_with_contents(&template_path, "{{email}} {{profile}}")?;
+
+            assert_eq!("me@home.com ", app.render_template(&template_path)?);
+            assert!(app.set_profile("server").is_err());
+            app.set_profile("work")?;
+            assert_eq!("me@work.com work", app.render_template(&template_path)?);
+
+            // the profile is remembered by the database
+            app.db.commit()?;
+            let other = App::from_config_read_only(app.cfg.clone())?;
+            assert_eq!(Some("work"), other.profile());
+            assert_eq!("me@work.com work", other.render_template(&template_path)?);
+
+            app.set_profile("")?;
+            assert_eq!(None, app.profile());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_deep_merge() {
+        let mut data = json!({
+            "colors": {"bg": "black", "fg": "white"},
+            "fonts": ["mono"],
+            "paths": ["~/bin"],
+            "plugins": ["a", "b"],
+            "editor": "vim",
+        });
+        data::deep_merge(
+            &mut data,
+            json!({
+                "colors": {"bg": "navy", "extra": {"x": null, "_merge": {}}},
+                "fonts": ["sans"],
+                "paths": ["~/.cargo/bin"],
+                "plugins": ["b", "c"],
+                "editor": null,
+                "_merge": {"paths": "prepend", "plugins": "union"},
+            }),
+        )
+        .unwrap();
+        assert_eq!(
+            json!({
+                "colors": {"bg": "navy", "fg": "white", "extra": {}},
+                "fonts": ["sans"],
+                "paths": ["~/.cargo/bin", "~/bin"],
+                "plugins": ["a", "b", "c"],
+            }),
+            data
+        );
+
+        data::deep_merge(
+            &mut data,
+            json!({"fonts": ["serif"], "_merge": {"fonts": "append"}}),
+        )
+        .unwrap();
+        assert_eq!(json!(["sans", "serif"]), data["fonts"]);
+
+        assert!(
+            data::deep_merge(&mut data, json!({"fonts": [], "_merge": {"fonts": "zip"}})).is_err()
+        );
+        assert!(data::deep_merge(&mut data, json!({"_merge": ["fonts"]})).is_err());
+    }
+
+    #[test]
+    fn test_template_deep_merge() {
+        pretty_err_catcher(|| {
+            let _ = pretty_env_logger::try_init();
+            let mut app = App::new_test()?;
+            app.cfg.profiles.insert(
+                "laptop".to_owned(),
+                json!({"colors": {"fg": "grey"}, "font": {"size": 10}}),
+            );
+            app.set_profile("laptop")?;
+            create_file_with_contents(
+                app.global_config_path(),
+                r#"{"colors": {"bg": "black", "fg": "white"}, "font": {"name": "mono", "size": 12}}"#,
+            )?;
+            let template_path = app.cfg.dot_path.join("mergetest");
+            create_file_with_contents(
+                &template_path,
+                "{{colors.bg}} {{colors.fg}} {{font.name}} {{font.size}}",
+            )?;
+            create_file_with_contents(
+                app.json_filename(&template_path),
+                r#"{"colors": {"bg": "navy"}, "font": {"name": null}}"#,
+            )?;
+
+            assert_eq!("navy grey  10", app.render_template(&template_path)?);
+
+            create_file_with_contents(app.json_filename(&template_path), "[]")?;
+            assert!(app.render_template(&template_path).is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_directory_data() {
+        pretty_err_catcher(|| {
+            let _ = pretty_env_logger::try_init();
+            let app = App::new_test()?;
+            let dot_path = app.cfg.dot_path.clone();
+            create_file_with_contents(
+                dot_path.join("_data.json"),
+                r#"{"a": 1, "b": 1, "c": 1}"#,
+            )?;
+            create_file_with_contents(
+                dot_path.join("config/i3/_data.json"),
+                r#"{"b": 2, "c": 2}"#,
+            )?;
+
+            let nested = dot_path.join("config/i3/config");
+            create_file_with_contents(&nested, "{{a}}{{b}}{{c}}")?;
+            create_file_with_contents(app.json_filename(&nested), r#"{"c": 3}"#)?;
+            assert_eq!("123", app.render_template(&nested)?);
+
+            let sibling = dot_path.join("config/polybar");
+            create_file_with_contents(&sibling, "{{a}}{{b}}{{c}}")?;
+            assert_eq!("111", app.render_template(&sibling)?);
+
+            let files: Vec<_> = app.files_to_process()?;
+            assert_eq!(vec![nested, sibling], files);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_partials() {
+        pretty_err_catcher(|| {
+            let _ = pretty_env_logger::try_init();
+            let app = App::new_test()?;
+            let dot_path = app.cfg.dot_path.clone();
+            create_file_with_contents(dot_path.join("_partials/colors"), "bg={{bg}}")?;
+            // hidden files such as swap files are not partials
+            std::fs::write(dot_path.join("_partials/.colors.swp"), [0xff, 0xfe, 0x00])?;
+            create_file_with_contents(
+                dot_path.join("_partials/sway/keys.conf"),
+                "bindsym {{mod}}+Return exec term",
+            )?;
+            create_file_with_contents(
+                dot_path.join("config/sway/_data.json"),
+                r#"{"mod": "Mod4"}"#,
+            )?;
+
+            let template_path = dot_path.join("config/sway/config");
+            create_file_with_contents(
+                &template_path,
+                "{{> sway/keys}}\n{{> colors bg=\"#000\"}}",
+            )?;
+            assert_eq!(
+                "bindsym Mod4+Return exec term\nbg=#000",
+                app.render_template(&template_path)?
+            );
+
+            let files: Vec<_> = app.files_to_process()?;
+            assert_eq!(vec![template_path], files);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_renderer_reuse() {
+        pretty_err_catcher(|| {
+            let _ = pretty_env_logger::try_init();
+            let app = App::new_test()?;
+            let dot_path = app.cfg.dot_path.clone();
+            create_file_with_contents(app.global_config_path(), r#"{"a": "global"}"#)?;
+            create_file_with_contents(dot_path.join("_partials/p"), "partial")?;
+            let renderer = app.renderer()?;
+
+            // global data and partials are loaded once, per-file data for every render
+            create_file_with_contents(app.global_config_path(), r#"{"a": "changed"}"#)?;
+            create_file_with_contents(dot_path.join("_partials/p"), "changed")?;
+            let first = dot_path.join("first");
+            create_file_with_contents(&first, "{{a}} {{> p}} {{b}}")?;
+            create_file_with_contents(app.json_filename(&first), r#"{"b": 1}"#)?;
+            let second = dot_path.join("second");
+            create_file_with_contents(&second, "{{a}} {{> p}} {{b}}")?;
+            create_file_with_contents(app.json_filename(&second), r#"{"b": 2}"#)?;
+
+            assert_eq!("global partial 1", app.render_template_with(&renderer, &first)?);
+            assert_eq!("global partial 2", app.render_template_with(&renderer, &second)?);
+            assert_eq!("changed changed 1", app.render_template(&first)?);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_strict_mode() {
+        pretty_err_catcher(|| {
+            let _ = pretty_env_logger::try_init();
+            let mut app = App::new_test()?;
+            let dot_path = app.cfg.dot_path.clone();
+            let template_path = dot_path.join("typo");
+            let result_path = app.filename_to_dotfile(&template_path)?;
+            create_file_with_contents(
+                &template_path,
+                "host={{facts.hostname}}{{#if missing}}!{{/if}}\nname={{hostnmae}}",
+            )?;
+            let rendered = format!("host={}\nname=", facts::hostname());
+            assert_eq!(rendered, app.render_template(&template_path)?);
+
+            app.set_strict(true);
+            match app.process_file(&template_path, &result_path) {
+                Err(Error(ErrorKind::UndefinedVariable(template, line, variable), _)) => {
+                    assert_eq!(template_path.display().to_string(), template);
+                    assert_eq!(2, line);
+                    assert_eq!("hostnmae", variable);
+                }
+                res => panic!("Expected an undefined variable error, got {:?}", res),
+            }
+            assert!(!result_path.exists());
+            assert!(!app.db.in_database(&result_path));
+
+            // the file's `_strict` key wins over the run setting
+            create_file_with_contents(app.json_filename(&template_path), r#"{"_strict": false}"#)?;
+            app.process_file(&template_path, &result_path)?;
+            assert_eq!(rendered, read_file(&result_path)?);
+
+            app.set_strict(false);
+            create_file_with_contents(app.json_filename(&template_path), r#"{"_strict": true}"#)?;
+            assert!(app.render_template(&template_path).is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_preview() {
+        pretty_err_catcher(|| {
+            let _ = pretty_env_logger::try_init();
+            let app = App::new_test()?;
+            let template_path = app.cfg.dot_path.join("config/theme");
+            let result_path = app.filename_to_dotfile(&template_path)?;
+            create_file_with_contents(
+                &template_path,
+                "{{facts.hostname}} {{colors.bg}} {{size}}{{#if server}} server{{/if}}",
+            )?;
+            create_file_with_contents(
+                app.json_filename(&template_path),
+                r#"{"colors": {"bg": "black"}, "size": 10}"#,
+            )?;
+            let host = facts::hostname();
+            assert_eq!(format!("{} black 10", host), app.preview(&template_path, None, &[])?);
+
+            let overrides = [
+                data::parse_assignment("colors.bg=#000")?,
+                data::parse_assignment("size=12")?,
+            ];
+            assert_eq!(
+                "server #000 12 server",
+                app.preview(&template_path, Some("server"), &overrides)?
+            );
+            assert!(!result_path.exists());
+            assert!(!app.db.in_database(&result_path));
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_data_formats() {
+        pretty_err_catcher(|| {
+            let _ = pretty_env_logger::try_init();
+            let first = App::new_test()?;
+            std::fs::remove_file(first.global_config_path())?;
+            create_file_with_contents(
+                first.global_config_path().with_extension("yaml"),
+                "# comments!\na: global\nb: global\nc: global\n",
+            )?;
+            // a global config in another format is not shadowed by a default json one
+            let app = App::from_config(first.cfg.clone())?;
+            assert!(!app.global_config_path().exists());
+            let dot_path = app.cfg.dot_path.clone();
+            create_file_with_contents(dot_path.join("config/_data.yml"), "b: directory\n")?;
+
+            let template_path = dot_path.join("config/formats");
+            create_file_with_contents(&template_path, "{{a}} {{b}} {{c}}")?;
+            create_file_with_contents(
+                dot_path.join("config/formats.toml"),
+                "# comments!\nc = \"file\"\n",
+            )?;
+            assert_eq!("global directory file", app.render_template(&template_path)?);
+
+            // toml and yaml files without a template next to them are templates themselves
+            let toml_template = dot_path.join("config/alacritty.toml");
+            create_file_with_contents(&toml_template, "font = \"{{a}}\"\n")?;
+            let files: Vec<_> = app.files_to_process()?;
+            assert_eq!(vec![toml_template, template_path.clone()], files);
+
+            create_file_with_contents(dot_path.join("config/formats.toml"), "c = ")?;
+            assert!(app.render_template(&template_path).is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_env_and_commands() {
+        pretty_err_catcher(|| {
+            let _ = pretty_env_logger::try_init();
+            let mut app = App::new_test()?;
+            std::env::set_var("ROTFILES_TEST_EXPOSED", "exposed");
+            std::env::set_var("ROTFILES_TEST_HIDDEN", "hidden");
+            app.cfg.env = vec![
+                "ROTFILES_TEST_EXPOSED".to_owned(),
+                "ROTFILES_TEST_UNSET".to_owned(),
+            ];
+            let counter = app.cfg.home_path.join("counter");
+            let run = format!("echo x >> {0}; wc -l < {0}", counter.display());
+            app.cfg.commands.insert(
+                "runs".to_owned(),
+                config::Command {
+                    run,
+                    timeout: None,
+                    cache: Some("1h".to_owned()),
+                },
+            );
+
+            let template_path = app.cfg.dot_path.join("runtimetest");
+            create_file_with_contents(
+                &template_path,
+                "{{env.ROTFILES_TEST_EXPOSED}}{{env.ROTFILES_TEST_HIDDEN}} {{commands.runs}}",
+            )?;
+            assert_eq!("exposed 1", app.render_template(&template_path)?);
+            // computed once per run
+            assert_eq!("exposed 1", app.render_template(&template_path)?);
+
+            // and cached between runs
+            let other = App::from_config_read_only(app.cfg.clone())?;
+            assert_eq!("exposed 1", other.render_template(&template_path)?);
+
+            app.cfg.commands.get_mut("runs").unwrap().cache = None;
+            let other = App::from_config_read_only(app.cfg.clone())?;
+            assert_eq!("exposed 2", other.render_template(&template_path)?);
+
+            app.cfg.commands.get_mut("runs").unwrap().run = "exit 1".to_owned();
+            let other = App::from_config_read_only(app.cfg.clone())?;
+            assert!(other.render_template(&template_path).is_err());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_facts() {
+        pretty_err_catcher(|| {
+            let _ = pretty_env_logger::try_init();
+            let app = App::new_test()?;
+            let template_path = app.cfg.dot_path.join("factstest");
+            create_file_with_contents(
+                &template_path,
+                "{{facts.hostname}} {{facts.arch}} {{#if (eq facts.family \"linux\")}}penguin{{/if}}",
+            )?;
+            assert_eq!(
+                format!("{} {} penguin", facts::hostname(), std::env::consts::ARCH),
+                app.render_template(&template_path)?
+            );
+            Ok(())
+        });
+    }
+
     #[test]
     fn test_template_no_local_json() {
         pretty_err_catcher(|| {
@@ -802,6 +2379,96 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_diff_file() {
+        pretty_err_catcher(|| {
+            let _ = pretty_env_logger::try_init();
+            let app = App::new_test()?;
+            let result_path = app.cfg.home_path.join(".difftest");
+            let template_path = app.dotfile_to_filename(&result_path)?;
+            let renderer = app.renderer()?;
+
+            create_file_with_contents(&template_path, "first\nsecond {{site}}\n")?;
+            create_file_with_contents(app.json_filename(&template_path), r#"{"site": "b"}"#)?;
+
+            let diff = app.diff_file(&renderer, &template_path, &result_path)?.unwrap();
+            assert!(diff.contains("--- /dev/null"));
+            assert!(diff.contains("+second b"));
+            assert!(!result_path.exists());
+
+            create_file_with_contents(&result_path, "first\nsecond a\n")?;
+            let diff = app.diff_file(&renderer, &template_path, &result_path)?.unwrap();
+            assert!(diff.contains("-second a\n+second b"));
+            assert!(diff.contains(" first"));
+            assert_eq!("first\nsecond a\n", read_file(&result_path)?);
+
+            create_file_with_contents(&result_path, "first\nsecond b\n")?;
+            assert_eq!(None, app.diff_file(&renderer, &template_path, &result_path)?);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_pull_file() {
+        pretty_err_catcher(|| {
+            let _ = pretty_env_logger::try_init();
+            let mut app = App::new_test()?;
+            let result_path = app.cfg.home_path.join(".pulltest");
+            let template_path = app.dotfile_to_filename(&result_path)?;
+
+            create_file_with_contents(&template_path, "name = {{name}}\nsize = 12\n")?;
+            create_file_with_contents(app.json_filename(&template_path), r#"{"name": "x"}"#)?;
+            app.process_file(&template_path, &result_path)?;
+
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            let edited = "name = x\nsize = 14 {{literal}}\n";
+            create_file_with_contents(&result_path, edited)?;
+            assert!(app.ensure_template_newer_than_file(&result_path).is_err());
+
+            app.pull_file(&result_path)?;
+            assert_eq!(
+                "name = {{name}}\nsize = 14 \\{{literal}}\n",
+                read_file(&template_path)?
+            );
+            assert_eq!(edited, app.render_template(&template_path)?);
+            app.ensure_template_newer_than_file(&result_path)?;
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_three_way_merge() {
+        pretty_err_catcher(|| {
+            let _ = pretty_env_logger::try_init();
+            let mut app = App::new_test()?;
+            let result_path = app.cfg.home_path.join(".mergetest");
+            let template_path = app.dotfile_to_filename(&result_path)?;
+            let json_path = app.json_filename(&template_path);
+
+            create_file_with_contents(&template_path, "a = {{x}}\nb\nc\n")?;
+            create_file_with_contents(&json_path, r#"{"x": 1}"#)?;
+            app.process_file(&template_path, &result_path)?;
+
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            create_file_with_contents(&result_path, "a = 1\nb\nc changed in home\n")?;
+
+            // nothing changed in the template, so there is nothing to merge
+            match app.process_file(&template_path, &result_path) {
+                Err(Error(ErrorKind::FileNewerThanTemplate(_), _)) => (),
+                _ => bail!("Deployed changes were not detected"),
+            }
+
+            create_file_with_contents(&json_path, r#"{"x": 2}"#)?;
+            app.process_file(&template_path, &result_path)?;
+            assert_eq!("a = 2\nb\nc changed in home\n", read_file(&result_path)?);
+            assert_eq!(
+                Some("a = 2\nb\nc\n".to_owned()),
+                app.db.get(&result_path).unwrap().last_rendered
+            );
+            Ok(())
+        });
+    }
+
     #[test]
     fn test_filename_to_dotfile() {
         let _ = pretty_env_logger::try_init();
@@ -909,9 +2576,8 @@ mod tests {
                 dot_file_path.metadata()?.modified()?
             );
 
-            let _res1 = app
-                .ensure_template_newer_than_file(&dot_file_path)
-                .chain_err(|| format!("Template falsely marked as older"))?;
+            app.ensure_template_newer_than_file(&dot_file_path)
+                .chain_err(|| "Template falsely marked as older")?;
             // let _res2: std::result::Result<(), ()> = match app.ensure_template_newer_than_file(
             //     &dot_file_path,
             // ) {
@@ -922,9 +2588,8 @@ mod tests {
             app.process_file(app.dotfile_to_filename(&dot_file_path)?, &dot_file_path)
                 .chain_err(|| "Error processing file")?;
 
-            let _res3 = app
-                .ensure_template_newer_than_file(&dot_file_path)
-                .chain_err(|| format!("Template falsely marked as older after processing"))?;
+            app.ensure_template_newer_than_file(&dot_file_path)
+                .chain_err(|| "Template falsely marked as older after processing")?;
             // let _res4: std::result::Result<(), ()> = match app.ensure_template_newer_than_file(
             //     &dot_file_path,
             // ) {
@@ -936,6 +2601,227 @@ mod tests {
         });
     }
 
+    #[test]
+    fn test_status() {
+        let _ = pretty_env_logger::try_init();
+        pretty_err_catcher(|| {
+            let mut app = App::new_test()?;
+            let tick = || std::thread::sleep(std::time::Duration::from_millis(100));
+
+            let dotfile_path = app.cfg.home_path.join(".statustest");
+            let template_path = app.dotfile_to_filename(&dotfile_path)?;
+            create_file_with_contents(&dotfile_path, "original")?;
+            app.add_file(&dotfile_path, false)?;
+            let entry = app.db.get(&dotfile_path).unwrap().clone();
+            assert_eq!(FileStatus::Clean, app.file_status(&entry)?);
+
+            tick();
+            create_file_with_contents(&template_path, "template edit")?;
+            assert_eq!(FileStatus::TemplateChanged, app.file_status(&entry)?);
+
+            create_file_with_contents(&dotfile_path, "home edit")?;
+            assert_eq!(FileStatus::Diverged, app.file_status(&entry)?);
+
+            std::fs::remove_file(&dotfile_path)?;
+            assert_eq!(FileStatus::DestinationMissing, app.file_status(&entry)?);
+
+            std::fs::remove_file(&template_path)?;
+            assert_eq!(FileStatus::TemplateMissing, app.file_status(&entry)?);
+
+            let other_path = app.cfg.home_path.join(".statustest2");
+            create_file_with_contents(&other_path, "original")?;
+            app.add_file(&other_path, true)?;
+            tick();
+            create_file_with_contents(&other_path, "home edit")?;
+
+            let statuses = app.status()?;
+            assert_eq!(
+                vec![
+                    (dotfile_path.clone(), FileStatus::TemplateMissing),
+                    (other_path.clone(), FileStatus::Modified)
+                ],
+                statuses
+            );
+
+            // a template that doesn't render is reported without hiding the others
+            create_file_with_contents(app.dotfile_to_filename(&other_path)?, "{{#if}}")?;
+            let statuses = app.status()?;
+            assert_eq!((dotfile_path, FileStatus::TemplateMissing), statuses[0]);
+            match statuses[1] {
+                (ref path, FileStatus::Error(ref message)) => {
+                    assert_eq!(&other_path, path);
+                    assert!(message.contains("Could not parse template"), "{}", message);
+                }
+                ref status => panic!("Expected an error, got {:?}", status),
+            }
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_bootstrap() {
+        let _ = pretty_env_logger::try_init();
+        pretty_err_catcher(|| {
+            let mut app = App::new_test()?;
+            let mut paths = Vec::new();
+            for name in &["adopt", "overwrite", "skip", "same", "new"] {
+                let dotfile_path = app.cfg.home_path.join(format!(".bootstrap{}", name));
+                let template_path = app.dotfile_to_filename(&dotfile_path)?;
+                create_file_with_contents(&template_path, "template")?;
+                if *name != "new" {
+                    let contents = if *name == "same" { "template" } else { "local" };
+                    create_file_with_contents(&dotfile_path, contents)?;
+                }
+                paths.push(dotfile_path);
+            }
+
+            let mut asked = Vec::new();
+            app.bootstrap(|path, diff| {
+                assert!(diff.contains("-local"));
+                assert!(diff.contains("+template"));
+                asked.push(path.to_owned());
+                let name = path.file_name().unwrap().to_string_lossy();
+                Ok(match name.trim_start_matches(".bootstrap") {
+                    "adopt" => Bootstrap::Adopt,
+                    "overwrite" => Bootstrap::Overwrite,
+                    _ => Bootstrap::Skip,
+                })
+            })?;
+            assert_eq!(paths[..3].to_vec(), asked);
+
+            let [adopt, overwrite, skip, same, new] = &paths[..] else {
+                unreachable!()
+            };
+            assert_eq!("local", read_file(adopt)?);
+            let entry = app.db.get(adopt).unwrap().clone();
+            assert_eq!(Some("template".to_owned()), entry.last_rendered);
+            assert_eq!(FileStatus::Modified, app.file_status(&entry)?);
+
+            assert_eq!("template", read_file(overwrite)?);
+            assert_eq!(1, app.list_backups(Some(overwrite))?.len());
+
+            assert_eq!("local", read_file(skip)?);
+            assert!(!app.db.in_database(skip));
+
+            let entry = app.db.get(same).unwrap().clone();
+            assert_eq!(FileStatus::Clean, app.file_status(&entry)?);
+            assert!(app.list_backups(Some(same))?.is_empty());
+
+            assert_eq!("template", read_file(new)?);
+
+            // undo forgets adopted files but leaves them in place
+            app.undo(None)?;
+            assert_eq!("local", read_file(adopt)?);
+            assert!(!app.db.in_database(adopt));
+            assert!(!app.db.in_database(same));
+            assert_eq!("template", read_file(same)?);
+            assert_eq!("local", read_file(overwrite)?);
+            assert!(!new.exists());
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_bootstrap_broken_template() {
+        let _ = pretty_env_logger::try_init();
+        pretty_err_catcher(|| {
+            let mut app = App::new_test()?;
+            let mut paths = Vec::new();
+            let templates = [("first", "template"), ("broken", "{{#if x}}"), ("last", "template")];
+            for (name, template) in &templates {
+                let dotfile_path = app.cfg.home_path.join(format!(".bootstrap{}", name));
+                let template_path = app.dotfile_to_filename(&dotfile_path)?;
+                create_file_with_contents(&template_path, template)?;
+                create_file_with_contents(&dotfile_path, "local")?;
+                paths.push(dotfile_path);
+            }
+
+            app.bootstrap(|_, _| Ok(Bootstrap::Adopt))?;
+
+            let [first, broken, last] = &paths[..] else {
+                unreachable!()
+            };
+            assert!(app.db.in_database(first));
+            assert!(app.db.in_database(last));
+            assert!(!app.db.in_database(broken));
+            assert_eq!("local", read_file(broken)?);
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let _ = pretty_env_logger::try_init();
+        pretty_err_catcher(|| {
+            let mut app = App::new_test()?;
+            let dotfile_path = app.cfg.home_path.join(".dryruntest");
+            let template_path = app.dotfile_to_filename(&dotfile_path)?;
+            create_file_with_contents(&dotfile_path, "deployed")?;
+
+            let mut dry = App::from_config_dry_run(app.cfg.clone())?;
+            dry.add_file(&dotfile_path, true)?;
+            assert!(!template_path.exists());
+            assert!(!app.json_filename(&template_path).exists());
+            let actions = dry.planned_actions();
+            assert!(actions.contains(&Action::WriteFile {
+                path: template_path.clone(),
+                bytes: "deployed".len()
+            }));
+            assert!(matches!(actions.last(), Some(Action::CommitDatabase(_))));
+
+            app.add_file(&dotfile_path, false)?;
+            app.db.commit()?;
+            create_file_with_contents(&template_path, "changed template")?;
+
+            let mut dry = App::from_config_dry_run(app.cfg.clone())?;
+            dry.process_all_files()?;
+            dry.remove_file(&dotfile_path)?;
+            assert_eq!("deployed", read_file(&dotfile_path)?);
+            assert!(template_path.exists());
+
+            let actions = dry.planned_actions();
+            assert!(actions
+                .iter()
+                .any(|a| matches!(a, Action::Backup { from, .. } if *from == dotfile_path)));
+            assert!(actions.contains(&Action::WriteFile {
+                path: dotfile_path.clone(),
+                bytes: "changed template".len()
+            }));
+            assert!(actions.contains(&Action::RemoveFile(template_path.clone())));
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn test_drift_uses_content_hash() {
+        let _ = pretty_env_logger::try_init();
+        pretty_err_catcher(|| {
+            let mut app = App::new_test()?;
+            let dotfile_path = app.cfg.home_path.join(".hashtest");
+            let template_path = app.dotfile_to_filename(&dotfile_path)?;
+            create_file_with_contents(&dotfile_path, "contents")?;
+            app.add_file(&dotfile_path, false)?;
+            let entry = app.db.get(&dotfile_path).unwrap().clone();
+
+            // rewriting identical contents only bumps the mtimes
+            std::thread::sleep(std::time::Duration::from_millis(100));
+            create_file_with_contents(&dotfile_path, "contents")?;
+            create_file_with_contents(&template_path, "contents")?;
+            assert_eq!(FileStatus::Clean, app.file_status(&entry)?);
+            app.ensure_template_newer_than_file(&dotfile_path)?;
+
+            // a real change is found even if the mtime went backwards
+            create_file_with_contents(&dotfile_path, "changed")?;
+            File::options()
+                .write(true)
+                .open(&dotfile_path)?
+                .set_modified(std::time::UNIX_EPOCH)?;
+            assert_eq!(FileStatus::Modified, app.file_status(&entry)?);
+            assert!(app.ensure_template_newer_than_file(&dotfile_path).is_err());
+            Ok(())
+        });
+    }
+
     fn assert_acceptable_difference(mut s1: std::time::SystemTime, mut s2: std::time::SystemTime) {
         if s2 < s1 {
             std::mem::swap(&mut s1, &mut s2)
@@ -1,33 +1,119 @@
+#![allow(clippy::result_large_err)]
 extern crate handlebars;
 extern crate serde_json;
 extern crate structopt;
 #[macro_use]
 extern crate log;
 extern crate pretty_env_logger;
-use std::path::PathBuf;
+use std::io::{IsTerminal, Write};
+use std::path::{Path, PathBuf};
 
 use structopt::StructOpt;
 
 use rotfiles::errors::*;
 
+#[derive(StructOpt)]
+struct Opt {
+    /// Print the changes that would be made instead of making them
+    #[structopt(long, global = true)]
+    dry_run: bool,
+
+    /// Profile from the config to render templates with, remembered for later runs
+    #[structopt(long, global = true, env = "ROTFILES_PROFILE")]
+    profile: Option<String>,
+
+    /// Config file to use instead of the one found in the usual places
+    #[structopt(long, global = true, parse(from_os_str))]
+    config: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    cmd: Rotfiles,
+}
+
 #[derive(StructOpt)]
 enum Rotfiles {
+    /// Create the config file and the directories rotfiles works with
+    Init {
+        /// Directory dotfiles are deployed to [default: $HOME]
+        #[structopt(long, parse(from_os_str))]
+        home: Option<PathBuf>,
+        /// Directory holding templates [default: ~/dotfiles]
+        #[structopt(long, parse(from_os_str))]
+        dot_path: Option<PathBuf>,
+        /// Directory holding backups [default: ~/.local/share/rotfiles/backup]
+        #[structopt(long, parse(from_os_str))]
+        backup_path: Option<PathBuf>,
+        /// Initialise a git repository in the templates directory
+        #[structopt(long)]
+        git: bool,
+    },
     Add { fname: PathBuf },
-    Update,
-    Edit { fname: PathBuf },
+    Update {
+        /// Ask what to do with files that exist but are not managed yet
+        #[structopt(long)]
+        bootstrap: bool,
+        /// Fail on undefined variables instead of rendering them empty
+        #[structopt(long)]
+        strict: bool,
+    },
+    Edit {
+        fname: PathBuf,
+        /// Fail on undefined variables instead of rendering them empty
+        #[structopt(long)]
+        strict: bool,
+    },
     Remove { fname: PathBuf },
+    /// Report which managed files drifted from their templates
+    Status,
+    /// Show what `update` would change, optionally only for given files
+    Diff { fnames: Vec<PathBuf> },
+    /// Print a template rendered with the data `update` would use, without deploying it
+    Render {
+        fname: PathBuf,
+        /// Override a variable, e.g. `--set colors.bg=#000000`
+        #[structopt(
+            long = "set",
+            number_of_values = 1,
+            parse(try_from_str = rotfiles::data::parse_assignment)
+        )]
+        set: Vec<serde_json::Value>,
+        /// Render as if on the machine with this hostname
+        #[structopt(long)]
+        host: Option<String>,
+    },
+    /// Fold changes made to a deployed file back into its template
+    Pull { fname: PathBuf },
+    /// List backups, optionally only those of a given file
+    Backups { fname: Option<PathBuf> },
+    /// Put a backed up version of a file back in place
+    Restore {
+        fname: PathBuf,
+        /// Restore the newest backup taken no later than this time
+        #[structopt(long)]
+        at: Option<String>,
+    },
+    /// Delete backups that the retention policy no longer keeps
+    Gc,
+    /// Revert all changes made by the last (or a given) `update` run
+    Undo {
+        id: Option<u64>,
+        /// List transactions that can be undone instead
+        #[structopt(long)]
+        list: bool,
+    },
 }
 
 fn main() {
-    match run() {
-        Err(ref e) => {
-            eprintln!("Error: {}", e);
-            for e in e.iter().skip(1) {
-                eprintln!("caused by: {}", e);
-            }
-            std::process::exit(1);
-        }
-        _ => (),
+    if let Err(ref e) = run() {
+        eprintln!("Error: {}", e);
+        print_causes(e);
+        std::process::exit(1);
+    }
+}
+
+fn print_causes(e: &rotfiles::errors::Error) {
+    for e in e.iter().skip(1) {
+        eprintln!("caused by: {}", e);
     }
 }
 
@@ -35,30 +121,275 @@ fn run() -> rotfiles::errors::Result<()> {
     pretty_env_logger::init();
     debug!("Program start");
 
-    let cfg = rotfiles::config::Config::from_file("/home/brych/.config/rotfiles/config.json")
-        .chain_err(|| "Could not load config.json")?;
-    let mut app = rotfiles::App::from_config(cfg).chain_err(|| "Could not instantiate App")?;
+    let opt = Opt::from_args();
+    let config_path = rotfiles::config::Config::locate(opt.config.as_deref());
+    if let Rotfiles::Init {
+        ref home,
+        ref dot_path,
+        ref backup_path,
+        git,
+    } = opt.cmd
+    {
+        return init(
+            &config_path,
+            home.clone(),
+            dot_path.clone(),
+            backup_path.clone(),
+            git,
+            opt.dry_run,
+        );
+    }
+    let cfg = rotfiles::config::Config::from_file(&config_path)
+        .chain_err(|| format!("Could not load config {}", config_path.display()))?;
 
-    let rfl = Rotfiles::from_args();
+    let rfl = opt.cmd;
+    match rfl {
+        Rotfiles::Status => {
+            let app = open_read_only(cfg, opt.profile.as_deref())?;
+            return print_status(&app);
+        }
+        Rotfiles::Diff { ref fnames } => {
+            let app = open_read_only(cfg, opt.profile.as_deref())?;
+            return print_diff(&app, fnames);
+        }
+        Rotfiles::Render {
+            ref fname,
+            ref set,
+            ref host,
+        } => {
+            let app = open_read_only(cfg, opt.profile.as_deref())?;
+            let (template, _) = app.resolve_targets(std::slice::from_ref(fname))?.remove(0);
+            let rendered = app
+                .preview(&template, host.as_deref(), set)
+                .chain_err(|| format!("Could not render {}", fname.display()))?;
+            print!("{}", rendered);
+            return Ok(());
+        }
+        Rotfiles::Backups { ref fname } => {
+            let app = open_read_only(cfg, opt.profile.as_deref())?;
+            for record in app.list_backups(fname.as_deref())? {
+                println!("{}", record);
+            }
+            return Ok(());
+        }
+        Rotfiles::Undo { list: true, .. } => {
+            let app = open_read_only(cfg, opt.profile.as_deref())?;
+            for transaction in app.transactions() {
+                println!("{}", transaction);
+            }
+            return Ok(());
+        }
+        _ => (),
+    }
+
+    let mut app = if opt.dry_run {
+        rotfiles::App::from_config_dry_run(cfg)
+    } else {
+        rotfiles::App::from_config(cfg)
+    }
+    .chain_err(|| "Could not instantiate App")?;
+    if let Some(ref profile) = opt.profile {
+        app.set_profile(profile)?;
+    }
     match rfl {
         Rotfiles::Add { fname } => {
             println!("Adding file: {}", fname.to_string_lossy());
             app.add_file(&fname, false)
                 .chain_err(|| format!("Could not add file {}", fname.display()))?;
         }
-        Rotfiles::Update => {
-            println!("Updating configuration");
-            app.process_all_files()
-                .chain_err(|| "Error while updating configuration")?;
+        Rotfiles::Update { bootstrap, strict } => {
+            app.set_strict(strict);
+            match app.profile() {
+                Some(profile) => println!("Updating configuration for profile {}", profile),
+                None => println!("Updating configuration"),
+            }
+            if bootstrap {
+                app.bootstrap(choose_bootstrap)
+            } else {
+                app.process_all_files()
+            }
+            .chain_err(|| "Error while updating configuration")?;
         }
-        Rotfiles::Edit { fname } => {
+        Rotfiles::Edit { fname, strict } => {
+            app.set_strict(strict);
             println!("Editing file: {}", fname.display());
             app.edit_file(&fname)?;
         }
         Rotfiles::Remove { fname } => {
             app.remove_file(&fname)?;
         }
+        Rotfiles::Pull { fname } => {
+            println!("Pulling changes from: {}", fname.display());
+            app.pull_file(&fname)
+                .chain_err(|| format!("Could not pull file {}", fname.display()))?;
+        }
+        Rotfiles::Restore { fname, at } => {
+            let at = match at {
+                Some(ref t) => Some(rotfiles::backup::parse_time(t)?),
+                None => None,
+            };
+            let backup = app
+                .restore_file(&fname, at)
+                .chain_err(|| format!("Could not restore {}", fname.display()))?;
+            println!("Restored {} from {}", fname.display(), backup.display());
+        }
+        Rotfiles::Gc => {
+            let removed = app.gc().chain_err(|| "Could not prune old backups")?;
+            for record in &removed {
+                println!("Removed {}", record.backup.display());
+            }
+            println!("Removed {} old backups", removed.len());
+        }
+        Rotfiles::Undo { id, .. } => {
+            let transaction = app.undo(id).chain_err(|| "Could not undo")?;
+            for change in &transaction.changes {
+                println!("Reverted {}", change.destination.display());
+            }
+            println!("Undid transaction {}", transaction.id);
+        }
+        Rotfiles::Init { .. }
+        | Rotfiles::Status
+        | Rotfiles::Diff { .. }
+        | Rotfiles::Render { .. }
+        | Rotfiles::Backups { .. } => unreachable!(),
+    }
+
+    if opt.dry_run {
+        print_plan(&app);
     }
 
     Ok(())
 }
+
+fn open_read_only(
+    cfg: rotfiles::config::Config,
+    profile: Option<&str>,
+) -> rotfiles::errors::Result<rotfiles::App> {
+    let mut app =
+        rotfiles::App::from_config_read_only(cfg).chain_err(|| "Could not instantiate App")?;
+    if let Some(profile) = profile {
+        app.set_profile(profile)?;
+    }
+    Ok(app)
+}
+
+fn print_plan(app: &rotfiles::App) {
+    let actions = app.planned_actions();
+    if actions.is_empty() {
+        println!("Dry run: nothing to do");
+    } else {
+        println!("Dry run: the following changes would be made:");
+        for action in actions {
+            println!("\t{}", action);
+        }
+    }
+}
+
+/// Reads a line from stdin, falling back to `default` on an empty answer
+/// or when stdin is not a terminal
+fn ask(prompt: &str, default: &str) -> rotfiles::errors::Result<String> {
+    if !std::io::stdin().is_terminal() {
+        return Ok(default.to_owned());
+    }
+    print!("{} [{}]: ", prompt, default);
+    std::io::stdout().flush()?;
+    let mut line = String::new();
+    std::io::stdin()
+        .read_line(&mut line)
+        .chain_err(|| "Could not read answer from stdin")?;
+    match line.trim() {
+        "" => Ok(default.to_owned()),
+        answer => Ok(answer.to_owned()),
+    }
+}
+
+fn choose_bootstrap(path: &Path, diff: &str) -> rotfiles::errors::Result<rotfiles::Bootstrap> {
+    println!("{} already exists and differs from its template:", path.display());
+    print!("{}", diff);
+    loop {
+        let answer = ask("[a]dopt it, [o]verwrite it or [s]kip it?", "s")?;
+        match answer.to_lowercase().chars().next() {
+            Some('a') => return Ok(rotfiles::Bootstrap::Adopt),
+            Some('o') => return Ok(rotfiles::Bootstrap::Overwrite),
+            Some('s') => return Ok(rotfiles::Bootstrap::Skip),
+            _ => println!("{} is not a correct answer", answer),
+        }
+    }
+}
+
+fn init(
+    config_path: &Path,
+    home: Option<PathBuf>,
+    dot_path: Option<PathBuf>,
+    backup_path: Option<PathBuf>,
+    git: bool,
+    dry_run: bool,
+) -> rotfiles::errors::Result<()> {
+    let path_or_ask = |given: Option<PathBuf>, prompt: &str, default: &Path| match given {
+        Some(p) => Ok(p),
+        None => ask(prompt, &default.to_string_lossy()).map(PathBuf::from),
+    };
+
+    let mut cfg = rotfiles::config::Config::with_home("~");
+    cfg.expand()?;
+    cfg.home_path = path_or_ask(home, "Home directory", &cfg.home_path)?;
+    cfg.expand()?;
+    let defaults = rotfiles::config::Config::with_home(&cfg.home_path);
+    cfg.dot_path = path_or_ask(dot_path, "Templates directory", &defaults.dot_path)?;
+    cfg.backup_path = path_or_ask(backup_path, "Backup directory", &defaults.backup_path)?;
+    cfg.expand()?;
+    let git = git || ask("Initialise a git repository there?", "n")?.starts_with(['y', 'Y']);
+
+    let mut app = rotfiles::App::init(cfg, config_path, dry_run)
+        .chain_err(|| "Could not initialise rotfiles")?;
+    if git {
+        app.init_repository()?;
+    }
+
+    if dry_run {
+        print_plan(&app);
+    } else {
+        println!("Wrote config {}", config_path.display());
+    }
+    Ok(())
+}
+
+fn print_status(app: &rotfiles::App) -> rotfiles::errors::Result<()> {
+    let statuses = app.status().chain_err(|| "Could not check file status")?;
+    let dirty: Vec<_> = statuses.iter().filter(|(_, s)| !s.is_clean()).collect();
+
+    if dirty.is_empty() {
+        println!("All {} managed files are clean", statuses.len());
+        return Ok(());
+    }
+
+    println!("Files out of sync with their templates:");
+    for (path, status) in &dirty {
+        println!("\t{:<18}{}", format!("{}:", status), path.display());
+        if let rotfiles::status::FileStatus::Error(ref message) = status {
+            println!("\t{:<18}{}", "", message);
+        }
+    }
+    println!(
+        "\n{} managed files, {} clean, {} changed",
+        statuses.len(),
+        statuses.len() - dirty.len(),
+        dirty.len()
+    );
+    Ok(())
+}
+
+fn print_diff(app: &rotfiles::App, fnames: &[PathBuf]) -> rotfiles::errors::Result<()> {
+    let renderer = app.renderer()?;
+    for (template, destination) in app.resolve_targets_with(&renderer, fnames)? {
+        match app.diff_file(&renderer, &template, &destination) {
+            Ok(Some(diff)) => print!("{}", diff),
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("Could not diff {}\n{}", destination.display(), e);
+                print_causes(&e);
+            }
+        }
+    }
+    Ok(())
+}
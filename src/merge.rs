@@ -0,0 +1,465 @@
+use similar::{capture_diff_slices, Algorithm, DiffOp};
+use std::ops::Range;
+
+fn lines(s: &str) -> Vec<&str> {
+    s.split_inclusive('\n').collect()
+}
+
+// A diff operation. Ops cover both texts in order, without gaps.
+struct Op {
+    old: Range<usize>,
+    new: Range<usize>,
+    equal: bool,
+}
+
+// Line diff from `old` to `new`, rebuilt from the lines similar matched.
+// The positions similar reports for insertions and deletions around moved
+// blank lines can be out of order, so only matches that move forward in both
+// texts are kept and everything between them becomes a change.
+fn diff(old: &[&str], new: &[&str]) -> Vec<Op> {
+    let mut anchors: Vec<(usize, usize)> = Vec::new();
+    for op in capture_diff_slices(Algorithm::Myers, old, new) {
+        if let DiffOp::Equal {
+            old_index,
+            new_index,
+            len,
+        } = op
+        {
+            for k in 0..len {
+                let (o, n) = (old_index + k, new_index + k);
+                if anchors.last().is_none_or(|&(lo, ln)| o > lo && n > ln) {
+                    anchors.push((o, n));
+                }
+            }
+        }
+    }
+
+    let mut res: Vec<Op> = Vec::new();
+    let (mut o, mut n) = (0, 0);
+    for (ao, an) in anchors {
+        if ao > o || an > n {
+            res.push(Op {
+                old: o..ao,
+                new: n..an,
+                equal: false,
+            });
+        }
+        match res.last_mut() {
+            Some(last) if last.equal => {
+                last.old.end += 1;
+                last.new.end += 1;
+            }
+            _ => res.push(Op {
+                old: ao..ao + 1,
+                new: an..an + 1,
+                equal: true,
+            }),
+        }
+        o = ao + 1;
+        n = an + 1;
+    }
+    if old.len() > o || new.len() > n {
+        res.push(Op {
+            old: o..old.len(),
+            new: n..new.len(),
+            equal: false,
+        });
+    }
+    res
+}
+
+/// Escapes handlebars expressions so that the text renders to itself.
+/// An escaped `\{{` swallows everything up to the matching `}}`,
+/// so closing braces must be left alone.
+pub fn sanitize(contents: &str) -> String {
+    contents.replace("{{", "\\{{")
+}
+
+// Template lines and the rendered lines they produced.
+// Literal lines map one to one, expressions map whole ranges.
+struct Unit {
+    template: Range<usize>,
+    rendered: Range<usize>,
+    dirty: bool,
+}
+
+fn units(template: &[&str], rendered: &[&str]) -> Vec<Unit> {
+    let mut res = Vec::new();
+    for op in diff(template, rendered) {
+        if op.equal {
+            res.extend(op.old.zip(op.new).map(|(t, r)| Unit {
+                template: t..t + 1,
+                rendered: r..r + 1,
+                dirty: false,
+            }));
+        } else {
+            res.push(Unit {
+                template: op.old,
+                rendered: op.new,
+                dirty: false,
+            });
+        }
+    }
+    res
+}
+
+// Maps line boundaries of `base` to line boundaries of a changed version,
+// as the position before and after any lines inserted at that boundary.
+// Boundaries inside changed lines map to the start of their replacement.
+// Both maps are non-decreasing and `before[p] <= after[p]`, so mapped
+// ranges are never reversed.
+struct BoundaryMap {
+    before: Vec<usize>,
+    after: Vec<usize>,
+}
+
+impl BoundaryMap {
+    fn new(base_len: usize, ops: &[Op]) -> Self {
+        let mut before = vec![None; base_len + 1];
+        let mut after = vec![0; base_len + 1];
+        let mut set = |p: usize, d: usize| {
+            before[p].get_or_insert(d);
+            after[p] = d;
+        };
+        for op in ops {
+            if op.equal {
+                for k in 0..=op.old.len() {
+                    set(op.old.start + k, op.new.start + k);
+                }
+            } else {
+                set(op.old.start, op.new.start);
+                for p in op.old.start + 1..op.old.end {
+                    set(p, op.new.start);
+                }
+                set(op.old.end, op.new.end);
+            }
+        }
+        // only reachable without any ops, when both sides are empty
+        let before = before
+            .iter()
+            .zip(after.iter())
+            .map(|(b, a)| b.unwrap_or(*a))
+            .collect();
+        Self { before, after }
+    }
+
+    fn range(&self, base: &Range<usize>) -> Range<usize> {
+        self.before[base.start]..self.after[base.end]
+    }
+}
+
+/// Folds changes made to a rendered file back into the template it came from.
+///
+/// `rendered` is the output of `template` with current data and `deployed`
+/// is that output after being edited. Template lines whose rendered output
+/// was left alone are kept verbatim, so `{{...}}` expressions survive unless
+/// the lines they produced were edited. Edited lines are escaped like freshly
+/// added files.
+pub fn reverse_merge(template: &str, rendered: &str, deployed: &str) -> String {
+    let template = lines(template);
+    let rendered = lines(rendered);
+    let deployed = lines(deployed);
+
+    let mut units = units(&template, &rendered);
+    let changes = diff(&rendered, &deployed);
+
+    let map = BoundaryMap::new(rendered.len(), &changes);
+    let insertions: Vec<usize> = changes
+        .iter()
+        .filter(|op| op.old.is_empty() && !op.new.is_empty())
+        .map(|op| op.old.start)
+        .collect();
+
+    for op in changes.iter().filter(|op| !op.equal) {
+        let r = op.old.clone();
+        for u in units.iter_mut() {
+            let overlaps = u.rendered.start < r.end && r.start < u.rendered.end;
+            let swallowed = r.start < u.rendered.start && u.rendered.start < r.end;
+            let split = r.is_empty() && u.rendered.start < r.start && r.start < u.rendered.end;
+            if overlaps || swallowed || split {
+                u.dirty = true;
+            }
+        }
+    }
+
+    let mut result = String::new();
+    let mut emitted = 0;
+    let mut emit_deployed = |result: &mut String, range: Range<usize>| {
+        for line in &deployed[range.start.max(emitted)..range.end.max(emitted)] {
+            result.push_str(&sanitize(line));
+        }
+        emitted = emitted.max(range.end);
+    };
+
+    let mut i = 0;
+    while i < units.len() {
+        let start = units[i].rendered.start;
+        if insertions.contains(&start) {
+            emit_deployed(&mut result, map.range(&(start..start)));
+        }
+        if !units[i].dirty {
+            for line in &template[units[i].template.clone()] {
+                result.push_str(line);
+            }
+            i += 1;
+            continue;
+        }
+        let mut end = units[i].rendered.end;
+        while i < units.len() && units[i].dirty {
+            end = units[i].rendered.end;
+            i += 1;
+        }
+        emit_deployed(&mut result, map.range(&(start..end)));
+    }
+    emit_deployed(&mut result, map.before[rendered.len()]..deployed.len());
+
+    result
+}
+
+/// Result of a three-way merge
+pub struct Merge {
+    pub text: String,
+    pub conflicts: usize,
+}
+
+/// Merges changes made to `base` in both `template` (the fresh render) and
+/// `deployed` (the file in $HOME). Overlapping changes that differ are
+/// written out between conflict markers.
+pub fn merge3(base: &str, template: &str, deployed: &str) -> Merge {
+    let base = lines(base);
+    let sides = [lines(template), lines(deployed)];
+
+    let ops: Vec<_> = sides.iter().map(|side| diff(&base, side)).collect();
+    let maps: Vec<_> = ops.iter().map(|o| BoundaryMap::new(base.len(), o)).collect();
+
+    // changed base ranges of both sides, tagged with the side they come from
+    let mut hunks: Vec<(Range<usize>, usize)> = ops
+        .iter()
+        .enumerate()
+        .flat_map(|(i, o)| {
+            o.iter()
+                .filter(|op| !op.equal)
+                .map(move |op| (op.old.clone(), i))
+        })
+        .collect();
+    hunks.sort_by_key(|(r, _)| (r.start, r.end));
+
+    let mut result = Merge {
+        text: String::new(),
+        conflicts: 0,
+    };
+    // conflict markers have to start on a fresh line
+    let push_lines = |text: &mut String, lines: &[&str]| {
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        for line in lines {
+            text.push_str(line);
+        }
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+    };
+
+    let mut copied = 0;
+    let mut i = 0;
+    while i < hunks.len() {
+        // cluster hunks that overlap or touch, those have to be resolved together
+        let mut cluster = hunks[i].0.clone();
+        let mut touched = [false; 2];
+        while i < hunks.len() && hunks[i].0.start <= cluster.end {
+            cluster.end = cluster.end.max(hunks[i].0.end);
+            touched[hunks[i].1] = true;
+            i += 1;
+        }
+
+        for line in &base[copied..cluster.start] {
+            result.text.push_str(line);
+        }
+        copied = cluster.end;
+
+        let texts: Vec<&[&str]> = sides
+            .iter()
+            .zip(maps.iter())
+            .map(|(side, map)| &side[map.range(&cluster)])
+            .collect();
+        match touched {
+            [true, true] if texts[0] != texts[1] => {
+                result.conflicts += 1;
+                push_lines(&mut result.text, &["<<<<<<< template\n"]);
+                push_lines(&mut result.text, texts[0]);
+                result.text.push_str("=======\n");
+                push_lines(&mut result.text, texts[1]);
+                result.text.push_str(">>>>>>> deployed\n");
+            }
+            [_, true] => {
+                for line in texts[1] {
+                    result.text.push_str(line);
+                }
+            }
+            _ => {
+                for line in texts[0] {
+                    result.text.push_str(line);
+                }
+            }
+        }
+    }
+    for line in &base[copied..] {
+        result.text.push_str(line);
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reverse_merge_keeps_expressions() {
+        let template = "font = {{font}}\nsize = 12\ncolor = {{color}}\n";
+        let rendered = "font = mono\nsize = 12\ncolor = red\n";
+        let deployed = "font = mono\nsize = 14\ncolor = red\n";
+        assert_eq!(
+            "font = {{font}}\nsize = 14\ncolor = {{color}}\n",
+            reverse_merge(template, rendered, deployed)
+        );
+    }
+
+    #[test]
+    fn test_reverse_merge_edited_expression() {
+        let template = "a\nfont = {{font}}\nb\n";
+        let rendered = "a\nfont = mono\nb\n";
+        let deployed = "a\nfont = sans {{weird}}\nb\n";
+        assert_eq!(
+            "a\nfont = sans \\{{weird}}\nb\n",
+            reverse_merge(template, rendered, deployed)
+        );
+    }
+
+    #[test]
+    fn test_reverse_merge_insert_and_delete() {
+        let template = "{{#if x}}\nfirst\n{{/if}}\nsecond\nthird\n";
+        let rendered = "\nfirst\n\nsecond\nthird\n";
+        let deployed = "\nfirst\nnew line\n\nthird\nlast\n";
+        assert_eq!(
+            "{{#if x}}\nfirst\nnew line\n{{/if}}\nthird\nlast\n",
+            reverse_merge(template, rendered, deployed)
+        );
+    }
+
+    #[test]
+    fn test_merge3_clean() {
+        let base = "a\nb\nc\nd\ne\n";
+        let template = "a\nB\nc\nd\ne\n";
+        let deployed = "a\nb\nc\nd\nE\nf\n";
+        let merge = merge3(base, template, deployed);
+        assert_eq!(0, merge.conflicts);
+        assert_eq!("a\nB\nc\nd\nE\nf\n", merge.text);
+    }
+
+    #[test]
+    fn test_merge3_same_change() {
+        let merge = merge3("a\nb\nc\n", "a\nx\nc\n", "a\nx\nc\n");
+        assert_eq!(0, merge.conflicts);
+        assert_eq!("a\nx\nc\n", merge.text);
+    }
+
+    #[test]
+    fn test_merge3_conflict() {
+        let merge = merge3("a\nb\nc", "a\nfrom template\nc", "a\nfrom home\nc");
+        assert_eq!(1, merge.conflicts);
+        assert_eq!(
+            "a\n<<<<<<< template\nfrom template\n=======\nfrom home\n>>>>>>> deployed\nc",
+            merge.text
+        );
+    }
+
+    #[test]
+    fn test_reverse_merge_unchanged() {
+        let template = "x = {{x}}\ny\n";
+        let rendered = "x = 1\ny\n";
+        assert_eq!(template, reverse_merge(template, rendered, rendered));
+    }
+
+    #[test]
+    fn test_reverse_merge_blank_lines() {
+        assert_eq!(
+            "# note\n\n\n",
+            reverse_merge("\n[core]\n", "\n[core]\n", "# note\n\n\n")
+        );
+        let template = "\n{{x}}\n\n";
+        let rendered = "\n1\n\n";
+        assert_eq!(
+            "# top\n\n{{x}}\n\n\n# bottom\n",
+            reverse_merge(template, rendered, "# top\n\n1\n\n\n# bottom\n")
+        );
+        assert_eq!("\n{{x}}\n", reverse_merge(template, rendered, "\n1\n"));
+    }
+
+    #[test]
+    fn test_merge3_blank_lines() {
+        let merge = merge3("a\n\n", "\nb\n\ne\n", "d");
+        assert_eq!(1, merge.conflicts);
+        let merge = merge3("a\n{{x}}\n", "a\n{{x}}\n", "{{x}}\n{{x}}\nb\n");
+        assert_eq!(0, merge.conflicts);
+        assert_eq!("{{x}}\n{{x}}\nb\n", merge.text);
+        let merge = merge3("\na\n", "top\n\na\n", "\na\n\nbottom\n");
+        assert_eq!(0, merge.conflicts);
+        assert_eq!("top\n\na\n\nbottom\n", merge.text);
+    }
+
+    // small texts made of few distinct lines, so that diffs are ambiguous
+    fn random_texts(count: usize) -> Vec<String> {
+        const LINES: [&str; 6] = ["", "a", "b", "[core]", "# note", "{{x}}"];
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move |n: usize| {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1_442_695_040_888_963_407);
+            (state >> 33) as usize % n
+        };
+        (0..count)
+            .map(|_| {
+                let mut text: String = (0..next(6))
+                    .map(|_| format!("{}\n", LINES[next(LINES.len())]))
+                    .collect();
+                if next(4) == 0 {
+                    text.push_str(LINES[next(LINES.len())]);
+                }
+                text
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_reverse_merge_fuzz() {
+        let texts = random_texts(40);
+        for a in &texts {
+            for b in &texts {
+                // without expressions, the template is the render
+                let plain = a.replace("{{x}}", "x");
+                assert_eq!(sanitize(b), reverse_merge(&plain, &plain, b));
+                for c in texts.iter().take(10) {
+                    reverse_merge(c, a, b);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_merge3_fuzz() {
+        let texts = random_texts(40);
+        for a in &texts {
+            for b in &texts {
+                let merge = merge3(a, a, b);
+                assert_eq!((b.as_str(), 0), (merge.text.as_str(), merge.conflicts));
+                let merge = merge3(a, b, a);
+                assert_eq!((b.as_str(), 0), (merge.text.as_str(), merge.conflicts));
+                for c in texts.iter().take(10) {
+                    merge3(a, b, c);
+                }
+            }
+        }
+    }
+}
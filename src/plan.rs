@@ -0,0 +1,33 @@
+use std::fmt;
+use std::path::PathBuf;
+
+/// A filesystem or database change that rotfiles would make.
+/// In dry-run mode these are collected instead of being executed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    CreateDir(PathBuf),
+    WriteFile { path: PathBuf, bytes: usize },
+    Copy { from: PathBuf, to: PathBuf },
+    Backup { from: PathBuf, to: PathBuf },
+    RemoveFile(PathBuf),
+    CommitDatabase(PathBuf),
+    Run(String),
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::CreateDir(p) => write!(f, "create directory {}", p.display()),
+            Action::WriteFile { path, bytes } => {
+                write!(f, "write {} ({} bytes)", path.display(), bytes)
+            }
+            Action::Copy { from, to } => write!(f, "copy {} -> {}", from.display(), to.display()),
+            Action::Backup { from, to } => {
+                write!(f, "back up {} -> {}", from.display(), to.display())
+            }
+            Action::RemoveFile(p) => write!(f, "remove {}", p.display()),
+            Action::CommitDatabase(p) => write!(f, "update database {}", p.display()),
+            Action::Run(cmd) => write!(f, "run {}", cmd),
+        }
+    }
+}
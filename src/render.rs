@@ -0,0 +1,112 @@
+use handlebars::{Handlebars, RenderError, TemplateRenderError};
+use serde_json::Value as Json;
+use std::cell::RefCell;
+use std::path::Path;
+
+use crate::errors::*;
+use crate::helpers;
+
+/// What every template rendered in a run shares: a Handlebars registry with
+/// the helpers and partials, and the data that doesn't depend on the template.
+/// Built once, so a run over many files doesn't reload them for each one.
+pub struct Renderer {
+    // strict mode is switched per template
+    handlebars: RefCell<Handlebars>,
+    global_data: Json,
+    strict: bool,
+}
+
+impl Renderer {
+    /// Registers the helpers, and every file under `partials_path` as a partial
+    /// named by its path without the extension. With `strict`, templates fail
+    /// to render if they use undefined variables.
+    pub fn new(partials_path: &Path, global_data: Json, strict: bool) -> Result<Self> {
+        let mut handlebars = Handlebars::new();
+        helpers::register(&mut handlebars);
+
+        let glob_path = partials_path.to_string_lossy() + "/**/*";
+        for path in glob::glob_with(&glob_path, crate::GLOB_OPTIONS)
+            .expect("Incorrect path")
+            .filter_map(std::result::Result::ok)
+            .filter(|p| p.is_file())
+        {
+            // `_partials/sway/keys.conf` becomes `{{> sway/keys}}`
+            let relative = path.strip_prefix(partials_path).unwrap().with_extension("");
+            let name = relative.to_string_lossy();
+            let source = crate::read_file(&path)?;
+            handlebars
+                .register_partial(&name, source)
+                .chain_err(|| format!("Could not parse partial: {}", path.display()))?;
+        }
+
+        Ok(Self {
+            handlebars: RefCell::new(handlebars),
+            global_data,
+            strict,
+        })
+    }
+
+    /// Facts, env, commands, the global config and the profile
+    pub fn global_data(&self) -> &Json {
+        &self.global_data
+    }
+
+    /// Renders `source`, the contents of the template at `template_path`.
+    /// A boolean `_strict` key in `data` overrides strict mode for this template.
+    pub fn render_template(
+        &self,
+        source: &str,
+        data: &Json,
+        template_path: &Path,
+    ) -> Result<String> {
+        let strict = match data.get("_strict") {
+            None => self.strict,
+            Some(Json::Bool(b)) => *b,
+            Some(_) => bail!("_strict must be a boolean"),
+        };
+        self.render_with(source, data, strict).map_err(|e| {
+            let template = template_path.display().to_string();
+            match e.as_render_error().and_then(undefined_variable) {
+                Some(variable) => {
+                    let line = e.as_render_error().and_then(|e| e.line_no).unwrap_or(0);
+                    ErrorKind::UndefinedVariable(template, line, variable).into()
+                }
+                None => describe(e, &template),
+            }
+        })
+    }
+
+    /// Renders a snippet such as a `_when` rule, never in strict mode
+    pub fn render(&self, source: &str, data: &Json) -> Result<String> {
+        self.render_with(source, data, false)
+            .map_err(|e| describe(e, source))
+    }
+
+    fn render_with(
+        &self,
+        source: &str,
+        data: &Json,
+        strict: bool,
+    ) -> std::result::Result<String, TemplateRenderError> {
+        self.handlebars.borrow_mut().set_strict_mode(strict);
+        self.handlebars.borrow().render_template(source, data)
+    }
+}
+
+fn describe(e: TemplateRenderError, name: &str) -> Error {
+    match e {
+        TemplateRenderError::TemplateError(e) => {
+            Error::with_chain(e, format!("Could not parse template {}", name))
+        }
+        e => Error::with_chain(e, format!("Could not render template {}", name)),
+    }
+}
+
+/// Name of the variable a strict mode error complains about
+fn undefined_variable(e: &RenderError) -> Option<String> {
+    let name = e
+        .desc
+        .strip_prefix("Variable \"")?
+        .strip_suffix("\" not found in strict mode.")?;
+    Some(name.to_owned())
+}
@@ -0,0 +1,41 @@
+use std::fmt;
+
+/// Drift state of a single managed file, as reported by `rotfiles status`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileStatus {
+    /// Destination matches what was last rendered and the template is unchanged
+    Clean,
+    /// Destination has been edited since the last render
+    Modified,
+    /// Template (or its json data) changed since the last render
+    TemplateChanged,
+    /// Both the destination and the template changed since the last render
+    Diverged,
+    /// Destination file no longer exists in $HOME
+    DestinationMissing,
+    /// Template file no longer exists in the dotfiles directory
+    TemplateMissing,
+    /// The file could not be checked, e.g. because its template doesn't render
+    Error(String),
+}
+
+impl FileStatus {
+    pub fn is_clean(&self) -> bool {
+        *self == FileStatus::Clean
+    }
+}
+
+impl fmt::Display for FileStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            FileStatus::Clean => "clean",
+            FileStatus::Modified => "modified",
+            FileStatus::TemplateChanged => "template changed",
+            FileStatus::Diverged => "diverged",
+            FileStatus::DestinationMissing => "deleted",
+            FileStatus::TemplateMissing => "template missing",
+            FileStatus::Error(_) => "error",
+        };
+        write!(f, "{}", s)
+    }
+}